@@ -0,0 +1,174 @@
+use crate::errors::BundlerClientError;
+use crate::primitives::user_operation::{
+    UserOperationByHash, UserOperationHash, UserOperationPartial, UserOperationReceipt,
+};
+use crate::types::{EstimateResult, Request};
+use ethers::types::{Address, U64};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JsonRpcResponse<T> {
+    pub jsonrpc: String,
+    pub id: u64,
+    pub result: Option<T>,
+    pub error: Option<JsonRpcError>
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl fmt::Display for JsonRpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+/// JSON-RPC client of an ERC-4337 bundler. Clones share the connection pool and the request id
+/// counter, so one client can serve the whole server.
+#[derive(Clone, Debug)]
+pub struct BundlerClient {
+    url: String,
+    http: reqwest::Client,
+    next_id: Arc<AtomicU64>,
+}
+
+impl BundlerClient {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self::with_http_client(url, reqwest::Client::new())
+    }
+
+    pub fn with_http_client(url: impl Into<String>, http: reqwest::Client) -> Self {
+        Self {
+            url: url.into(),
+            http,
+            next_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Calls `method`, `None` when the bundler answers with a null result.
+    pub async fn request<P, R>(&self, method: &str, params: P) -> Result<Option<R>, BundlerClientError>
+    where
+        P: Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let request = Request {
+            jsonrpc: "2.0".to_string(),
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            method: method.to_string(),
+            params,
+        };
+        let response = self.http.post(&self.url).json(&request).send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+
+        let decoded: JsonRpcResponse<R> = match serde_json::from_slice(&body) {
+            Ok(decoded) => decoded,
+            // Proxies in front of bundlers answer some failures with plain HTTP errors.
+            Err(_) if !status.is_success() => return Err(BundlerClientError::Http(status)),
+            Err(err) => return Err(BundlerClientError::Decode(method.to_string(), err)),
+        };
+        if decoded.id != request.id {
+            return Err(BundlerClientError::IdMismatch(request.id, decoded.id));
+        }
+        match decoded.error {
+            Some(error) => Err(BundlerClientError::Rpc(error)),
+            None => Ok(decoded.result),
+        }
+    }
+
+    /// Calls a method whose result is never null.
+    pub async fn call<P, R>(&self, method: &str, params: P) -> Result<R, BundlerClientError>
+    where
+        P: Serialize + Send + Sync,
+        R: DeserializeOwned,
+    {
+        self.request(method, params)
+            .await?
+            .ok_or_else(|| BundlerClientError::MissingResult(method.to_string()))
+    }
+
+    pub async fn chain_id(&self) -> Result<U64, BundlerClientError> {
+        self.call("eth_chainId", json!([])).await
+    }
+
+    pub async fn supported_entry_points(&self) -> Result<Vec<Address>, BundlerClientError> {
+        self.call("eth_supportedEntryPoints", json!([])).await
+    }
+
+    pub async fn estimate_user_operation_gas(
+        &self,
+        user_operation: &UserOperationPartial,
+        entry_point: Address,
+    ) -> Result<EstimateResult, BundlerClientError> {
+        self.call("eth_estimateUserOperationGas", json!([user_operation, entry_point])).await
+    }
+
+    pub async fn send_user_operation(
+        &self,
+        user_operation: &UserOperationPartial,
+        entry_point: Address,
+    ) -> Result<UserOperationHash, BundlerClientError> {
+        self.call("eth_sendUserOperation", json!([user_operation, entry_point])).await
+    }
+
+    /// `None` while the operation is pending or unknown to the bundler.
+    pub async fn get_user_operation_receipt(
+        &self,
+        user_operation_hash: &UserOperationHash,
+    ) -> Result<Option<UserOperationReceipt>, BundlerClientError> {
+        self.request("eth_getUserOperationReceipt", json!([user_operation_hash])).await
+    }
+
+    /// `None` when the bundler does not know the operation.
+    pub async fn get_user_operation_by_hash(
+        &self,
+        user_operation_hash: &UserOperationHash,
+    ) -> Result<Option<UserOperationByHash>, BundlerClientError> {
+        self.request("eth_getUserOperationByHash", json!([user_operation_hash])).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_null_result_and_error_with_data() {
+        let pending: JsonRpcResponse<UserOperationReceipt> =
+            serde_json::from_value(json!({"jsonrpc": "2.0", "id": 3, "result": null})).unwrap();
+        assert!(pending.result.is_none() && pending.error.is_none());
+
+        let failed: JsonRpcResponse<UserOperationHash> = serde_json::from_value(json!({
+            "jsonrpc": "2.0",
+            "id": 4,
+            "error": {"code": -32500, "message": "AA21 didn't pay prefund", "data": {"reason": "x"}},
+        }))
+        .unwrap();
+        let error = failed.error.unwrap();
+        assert_eq!(error.code, -32500);
+        assert_eq!(error.data, Some(json!({"reason": "x"})));
+        assert_eq!(error.to_string(), "AA21 didn't pay prefund (-32500)");
+    }
+
+    #[test]
+    fn clones_share_request_ids() {
+        let client = BundlerClient::new("http://localhost:4337");
+        let clone = client.clone();
+        assert_eq!(client.next_id.fetch_add(1, Ordering::Relaxed), 1);
+        assert_eq!(clone.next_id.fetch_add(1, Ordering::Relaxed), 2);
+        assert_eq!(clone.url(), "http://localhost:4337");
+    }
+}
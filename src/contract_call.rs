@@ -0,0 +1,194 @@
+use ethers::{
+    abi::{
+        token::{LenientTokenizer, Tokenizer},
+        Abi, AbiParser, Function, HumanReadableParser, ParamType, Token,
+    },
+    types::{Bytes, U256},
+};
+use serde_json::Value;
+
+/// Parses the function to call from either
+/// - a signature such as `transfer(address,uint256)` or `function transfer(address to, uint256 amount)`,
+/// - a JSON ABI item of a function, or
+/// - a JSON ABI that contains exactly one function.
+pub fn parse_function(abi_or_signature: &str) -> anyhow::Result<Function> {
+    let input = abi_or_signature.trim();
+
+    if input.starts_with('{') || input.starts_with('[') {
+        let abi_json = if input.starts_with('{') {
+            format!("[{}]", input)
+        } else {
+            input.to_string()
+        };
+        let abi: Abi = serde_json::from_str(&abi_json)?;
+        let mut functions = abi.functions();
+        return match (functions.next(), functions.next()) {
+            (Some(function), None) => Ok(function.clone()),
+            (None, _) => Err(anyhow::anyhow!("ABI does not contain a function")),
+            (Some(_), Some(_)) => Err(anyhow::anyhow!(
+                "ABI contains more than one function, pass a single ABI item or a function signature"
+            )),
+        };
+    }
+
+    // The lexer understands tuple parameters, the shorthand parser is the more lenient fallback.
+    let declaration = if input.starts_with("function ") {
+        input.to_string()
+    } else {
+        format!("function {}", input)
+    };
+    match HumanReadableParser::parse_function(&declaration) {
+        Ok(function) => Ok(function),
+        Err(_) => Ok(AbiParser::default().parse_function(input)?),
+    }
+}
+
+/// Converts a JSON argument into a token of type `kind`.
+///
+/// Scalars may be given as JSON strings, numbers or booleans; `uint` accepts decimal, `0x` hex and
+/// unit suffixes like `1.5 ether`. Integers must fit in the declared `uintN` / `intN` width.
+/// Arrays and tuples may be given as JSON arrays.
+pub fn tokenize_arg(kind: &ParamType, arg: &Value) -> anyhow::Result<Token> {
+    match (kind, arg) {
+        (ParamType::Array(inner), Value::Array(items)) => Ok(Token::Array(
+            items.iter().map(|item| tokenize_arg(inner, item)).collect::<anyhow::Result<_>>()?,
+        )),
+        (ParamType::FixedArray(inner, len), Value::Array(items)) => {
+            if items.len() != *len {
+                return Err(anyhow::anyhow!("expected {} items for {}, got {}", len, kind, items.len()));
+            }
+            Ok(Token::FixedArray(
+                items.iter().map(|item| tokenize_arg(inner, item)).collect::<anyhow::Result<_>>()?,
+            ))
+        },
+        (ParamType::Tuple(kinds), Value::Array(items)) => {
+            if items.len() != kinds.len() {
+                return Err(anyhow::anyhow!("expected {} fields for {}, got {}", kinds.len(), kind, items.len()));
+            }
+            Ok(Token::Tuple(
+                kinds.iter().zip(items).map(|(kind, item)| tokenize_arg(kind, item)).collect::<anyhow::Result<_>>()?,
+            ))
+        },
+        (_, Value::String(text)) => tokenize_str(kind, text),
+        (_, Value::Number(number)) => tokenize_str(kind, &number.to_string()),
+        (_, Value::Bool(flag)) => tokenize_str(kind, &flag.to_string()),
+        _ => Err(anyhow::anyhow!("cannot convert {} to {}", arg, kind)),
+    }
+}
+
+fn tokenize_str(kind: &ParamType, text: &str) -> anyhow::Result<Token> {
+    let token = match (kind, text.strip_prefix("0x")) {
+        (ParamType::Uint(_), Some(hex)) => Token::Uint(U256::from_str_radix(hex, 16)?),
+        _ => LenientTokenizer::tokenize(kind, text)
+            .map_err(|e| anyhow::anyhow!("invalid {} argument {:?}: {}", kind, text, e))?,
+    };
+    if !fits(kind, &token) {
+        return Err(anyhow::anyhow!("{} argument {:?} is out of range", kind, text));
+    }
+    Ok(token)
+}
+
+/// Whether an integer token fits in its declared width. The tokenizer parses every `uintN` and
+/// `intN` as 256 bits, and `encode` would emit a word the contract reverts on or reads truncated.
+fn fits(kind: &ParamType, token: &Token) -> bool {
+    match (kind, token) {
+        (ParamType::Uint(bits), Token::Uint(value)) => value.bits() <= *bits,
+        // Two's complement: a negative value fits when its complement `-value - 1` does.
+        (ParamType::Int(bits), Token::Int(value)) => {
+            let magnitude = if value.bit(255) { !*value } else { *value };
+            magnitude.bits() < *bits
+        },
+        _ => true,
+    }
+}
+
+/// ABI-encodes a call to `function` with JSON `args`.
+pub fn encode_function_call(function: &Function, args: &[Value]) -> anyhow::Result<Bytes> {
+    if function.inputs.len() != args.len() {
+        return Err(anyhow::anyhow!(
+            "{} expects {} arguments, got {}",
+            function.signature(),
+            function.inputs.len(),
+            args.len()
+        ));
+    }
+
+    let tokens = function
+        .inputs
+        .iter()
+        .zip(args)
+        .map(|(param, arg)| tokenize_arg(&param.kind, arg))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(function.encode_input(&tokens)?.into())
+}
+
+/// ABI-encodes a call described by `abi_or_signature` (see [`parse_function`]) with JSON `args`.
+pub fn encode_call(abi_or_signature: &str, args: &[Value]) -> anyhow::Result<Bytes> {
+    encode_function_call(&parse_function(abi_or_signature)?, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TRANSFER: &str = "0xa9059cbb000000000000000000000000c0c374f049f2e0036b48d93346038f0133b8f00f00000000000000000000000000000000000000000000000000000000000f4240";
+
+    #[test]
+    fn encodes_from_signature_with_string_args() {
+        let args = [json!("0xc0c374f049f2e0036B48D93346038f0133B8f00F"), json!("1000000")];
+        assert_eq!(encode_call("transfer(address,uint256)", &args).unwrap(), TRANSFER.parse::<Bytes>().unwrap());
+        assert_eq!(
+            encode_call("function transfer(address to, uint256 amount)", &args).unwrap(),
+            TRANSFER.parse::<Bytes>().unwrap()
+        );
+    }
+
+    #[test]
+    fn encodes_from_abi_item_with_hex_and_number_args() {
+        let abi = r#"{"type":"function","name":"transfer","inputs":[{"name":"to","type":"address"},{"name":"amount","type":"uint256"}],"outputs":[{"name":"","type":"bool"}],"stateMutability":"nonpayable"}"#;
+        let args = [json!("0xc0c374f049f2e0036B48D93346038f0133B8f00F"), json!("0xf4240")];
+        assert_eq!(encode_call(abi, &args).unwrap(), TRANSFER.parse::<Bytes>().unwrap());
+        let args = [json!("0xc0c374f049f2e0036B48D93346038f0133B8f00F"), json!(1000000)];
+        assert_eq!(encode_call(&format!("[{}]", abi), &args).unwrap(), TRANSFER.parse::<Bytes>().unwrap());
+    }
+
+    #[test]
+    fn encodes_arrays_and_tuples() {
+        let function = parse_function("f(address[],(uint256,bool))").unwrap();
+        let encoded = encode_function_call(
+            &function,
+            &[json!(["0xc0c374f049f2e0036B48D93346038f0133B8f00F"]), json!(["1 gwei", true])],
+        )
+        .unwrap();
+        let tokens = function.decode_input(&encoded[4..]).unwrap();
+        assert_eq!(tokens[1], Token::Tuple(vec![Token::Uint(U256::from(1_000_000_000u64)), Token::Bool(true)]));
+    }
+
+    #[test]
+    fn rejects_integers_wider_than_their_type() {
+        let uint8 = ParamType::Uint(8);
+        assert_eq!(tokenize_arg(&uint8, &json!("255")).unwrap(), Token::Uint(U256::from(255u64)));
+        assert_eq!(tokenize_arg(&uint8, &json!("0xff")).unwrap(), Token::Uint(U256::from(255u64)));
+        assert!(tokenize_arg(&uint8, &json!("256")).is_err());
+        assert!(tokenize_arg(&uint8, &json!("0x100")).is_err());
+        assert!(tokenize_arg(&ParamType::Uint(128), &json!("1 ether")).is_ok());
+        assert!(tokenize_arg(&ParamType::Uint(48), &json!(u64::MAX)).is_err());
+
+        let int8 = ParamType::Int(8);
+        assert_eq!(tokenize_arg(&int8, &json!(127)).unwrap(), Token::Int(U256::from(127u64)));
+        assert_eq!(tokenize_arg(&int8, &json!(-128)).unwrap(), Token::Int(!U256::from(127u64)));
+        assert!(tokenize_arg(&int8, &json!(128)).is_err());
+        assert!(tokenize_arg(&int8, &json!(-129)).is_err());
+        assert!(tokenize_arg(&ParamType::Int(256), &json!("-1")).is_ok());
+
+        let args = [json!("0xc0c374f049f2e0036B48D93346038f0133B8f00F"), json!("0x1000000000000000000")];
+        assert!(encode_call("transfer(address,uint64)", &args).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_argument_count() {
+        assert!(encode_call("transfer(address,uint256)", &[json!("0xc0c374f049f2e0036B48D93346038f0133B8f00F")]).is_err());
+    }
+}
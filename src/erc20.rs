@@ -0,0 +1,173 @@
+use crate::primitives::execution::Execution;
+use ethers::{
+    abi::AbiEncode,
+    contract::abigen,
+    types::{Address, Bytes, Signature, H256, U256},
+};
+use serde::{Serialize, Deserialize};
+
+abigen!(
+    IERC20Permit,
+    r#"[
+        function transfer(address to, uint256 amount) external returns (bool)
+        function approve(address spender, uint256 amount) external returns (bool)
+        function transferFrom(address from, address to, uint256 amount) external returns (bool)
+        function permit(address owner, address spender, uint256 value, uint256 deadline, uint8 v, bytes32 r, bytes32 s) external
+        function allowance(address owner, address spender) external view returns (uint256)
+        function balanceOf(address account) external view returns (uint256)
+        function nonces(address owner) external view returns (uint256)
+    ]"#
+);
+
+/// EIP-2612 permit signed by `owner`, allowing the smart account to spend `value` until `deadline`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Erc20Permit {
+    pub owner: Address,
+    pub value: U256,
+    pub deadline: U256,
+    /// 65 bytes `r ++ s ++ v` signature over the EIP-712 `Permit` message.
+    pub signature: Bytes,
+}
+
+/// ERC-20 operation executed by the smart account.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Erc20Operation {
+    #[serde(rename_all = "camelCase")]
+    Transfer { token: Address, to: Address, amount: U256 },
+    #[serde(rename_all = "camelCase")]
+    Approve { token: Address, spender: Address, amount: U256 },
+    #[serde(rename_all = "camelCase")]
+    TransferFrom { token: Address, from: Address, to: Address, amount: U256 },
+    /// `permit` to the account followed by `transferFrom(owner, to, permit.value)`.
+    #[serde(rename_all = "camelCase")]
+    PermitTransferFrom { token: Address, permit: Erc20Permit, to: Address },
+}
+
+impl Erc20Operation {
+    /// Executions performing this operation from `account`.
+    pub fn executions(&self, account: Address) -> anyhow::Result<Vec<Execution>> {
+        let executions = match self {
+            Erc20Operation::Transfer { token, to, amount } => vec![transfer(*token, *to, *amount)],
+            Erc20Operation::Approve { token, spender, amount } => vec![approve(*token, *spender, *amount)],
+            Erc20Operation::TransferFrom { token, from, to, amount } => {
+                vec![transfer_from(*token, *from, *to, *amount)]
+            },
+            Erc20Operation::PermitTransferFrom { token, permit: signed, to } => vec![
+                permit(*token, account, signed)?,
+                transfer_from(*token, signed.owner, *to, signed.value),
+            ],
+        };
+        Ok(executions)
+    }
+}
+
+/// Executions of all `operations`, in order.
+pub fn executions(account: Address, operations: &[Erc20Operation]) -> anyhow::Result<Vec<Execution>> {
+    let mut executions = Vec::new();
+    for operation in operations {
+        executions.extend(operation.executions(account)?);
+    }
+    Ok(executions)
+}
+
+pub fn transfer(token: Address, to: Address, amount: U256) -> Execution {
+    let call = TransferCall { to, amount };
+    Execution::new(token, U256::zero(), call.encode().into())
+}
+
+pub fn approve(token: Address, spender: Address, amount: U256) -> Execution {
+    let call = ApproveCall { spender, amount };
+    Execution::new(token, U256::zero(), call.encode().into())
+}
+
+pub fn transfer_from(token: Address, from: Address, to: Address, amount: U256) -> Execution {
+    let call = TransferFromCall { from, to, amount };
+    Execution::new(token, U256::zero(), call.encode().into())
+}
+
+/// `permit(owner, spender, value, deadline, v, r, s)` from the signature in `signed`.
+pub fn permit(token: Address, spender: Address, signed: &Erc20Permit) -> anyhow::Result<Execution> {
+    let signature = Signature::try_from(signed.signature.as_ref())?;
+    // Some signers return the recovery id as 0/1 instead of 27/28.
+    let v = if signature.v < 27 { signature.v + 27 } else { signature.v };
+
+    let mut r = [0u8; 32];
+    signature.r.to_big_endian(&mut r);
+    let mut s = [0u8; 32];
+    signature.s.to_big_endian(&mut s);
+
+    let call = PermitCall {
+        owner: signed.owner,
+        spender,
+        value: signed.value,
+        deadline: signed.deadline,
+        v: u8::try_from(v)?,
+        r: H256::from(r).into(),
+        s: H256::from(s).into(),
+    };
+    Ok(Execution::new(token, U256::zero(), call.encode().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token() -> Address {
+        "0x1c7D4B196Cb0C7B01d743Fbc6116a902379C7238".parse().unwrap()
+    }
+
+    fn to() -> Address {
+        "0xc0c374f049f2e0036B48D93346038f0133B8f00F".parse().unwrap()
+    }
+
+    #[test]
+    fn encodes_transfer() {
+        let execution = transfer(token(), to(), U256::from(1_000_000u64));
+        assert_eq!(execution.target, token());
+        assert!(execution.value.is_zero());
+        assert_eq!(
+            execution.call_data,
+            "0xa9059cbb000000000000000000000000c0c374f049f2e0036b48d93346038f0133b8f00f00000000000000000000000000000000000000000000000000000000000f4240".parse::<Bytes>().unwrap()
+        );
+    }
+
+    #[test]
+    fn permit_transfer_from_is_permit_then_transfer_from() {
+        let account: Address = "0x8F3B5C7B0BcBb6A3bA3E12ABB1e1F5BbA5b9a6E3".parse().unwrap();
+        let owner: Address = "0x0000000000000000000000000000000000000001".parse().unwrap();
+        let mut signature = vec![0x11u8; 64];
+        signature.push(0x01);
+        let operation = Erc20Operation::PermitTransferFrom {
+            token: token(),
+            permit: Erc20Permit {
+                owner,
+                value: U256::from(5u64),
+                deadline: U256::MAX,
+                signature: signature.into(),
+            },
+            to: to(),
+        };
+
+        let executions = operation.executions(account).unwrap();
+        assert_eq!(executions.len(), 2);
+        assert_eq!(executions[0].call_data[..4], [0xd5, 0x05, 0xac, 0xcf]);
+        let permit_call = <PermitCall as ethers::abi::AbiDecode>::decode(&executions[0].call_data).unwrap();
+        assert_eq!(permit_call.spender, account);
+        assert_eq!(permit_call.v, 28);
+        assert_eq!(executions[1], transfer_from(token(), owner, to(), U256::from(5u64)));
+    }
+
+    #[test]
+    fn deserializes_tagged_operations() {
+        let operation: Erc20Operation = serde_json::from_value(serde_json::json!({
+            "type": "approve",
+            "token": token(),
+            "spender": to(),
+            "amount": "0x10",
+        }))
+        .unwrap();
+        assert_eq!(operation, Erc20Operation::Approve { token: token(), spender: to(), amount: U256::from(16u64) });
+    }
+}
@@ -1,5 +1,12 @@
 use thiserror::Error;
-use ethers::providers::Middleware;
+use regex::Regex;
+use std::fmt;
+use ethers::{providers::Middleware, types::{Address, Bytes, U256}};
+use crate::primitives::mode_code::ModeCode;
+use crate::primitives::module::ModuleType;
+use crate::primitives::user_operation::UserOperationHash;
+use std::time::Duration;
+use crate::bundler_client::JsonRpcError;
 // In the implementation of example ethers-userop, they import ethers but I can not confim Middleware trait in alloy.rs, so here I will skip to use middleware but maybe we need that in the future
 
 #[derive(Debug, Clone, Error)]
@@ -14,19 +21,355 @@ pub enum UserOpMiddlewareError<M: Middleware> {
     #[error(transparent)]
     UserOpBuilderError(UserOpBuilderError<M>),
 
-    #[error("Pre-verification gas not enough: calculated: {0}, provided: {1}")]
+    #[error("Pre-verification gas not enough: provided: {0}, calculated: {1}")]
     PreVerificationGasError(u64, u64),
 
-    #[error("Call gas limit not enough: calculated: {0}, provided: {1}")]
+    #[error("Call gas limit not enough: provided: {0}, calculated: {1}")]
     CallGasLimitError(u64, u64),
 
-    #[error("Verification gas limit not enough")]
-    VerificationGasLimitError,
+    #[error("Verification gas limit not enough: {0}")]
+    VerificationGasLimitError(BundlerRejection),
+
+    #[error("Rejected by EntryPoint validation: {0}")]
+    EntryPointRejected(BundlerRejection),
+
+    #[error("Rejected by the paymaster: {0}")]
+    PaymasterRejected(BundlerRejection),
+
+    #[error("Banned opcode or storage access during validation: {0}")]
+    BannedOpcode(BundlerRejection),
+
+    #[error("Outside the validity time range: {0}")]
+    OutOfTimeRange(BundlerRejection),
+
+    #[error("Entity throttled or banned: {0}")]
+    EntityThrottled(BundlerRejection),
+
+    #[error("Entity stake or unstake delay too low: {0}")]
+    InsufficientStake(BundlerRejection),
+
+    #[error("Unsupported signature aggregator: {0}")]
+    UnsupportedAggregator(BundlerRejection),
+
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(BundlerRejection),
+
+    #[error("Execution reverted: {0}")]
+    ExecutionReverted(BundlerRejection),
+
+    #[error("Invalid user operation fields: {0}")]
+    InvalidUserOperation(BundlerRejection),
+
+    #[error("Bundler error: {0}")]
+    BundlerError(BundlerRejection),
+
+    #[error("Account {0:?} is not deployed and its factory data is unknown")]
+    AccountNotDeployed(Address),
+
+    #[error("{0} batches cannot run in parallel on {1} nonce lanes")]
+    TooManyParallelBatches(usize, u32),
+
+    #[error("Account {0:?} is not the address {1:?} the factory deploys for the configured salt and modules")]
+    CounterfactualAddressMismatch(Address, Address),
+
+    #[error("Execution mode {0} is not supported by account {1:?}")]
+    UnsupportedExecutionMode(ModeCode, Address),
+
+    #[error("The {0} module {1:?} is already installed on account {2:?}")]
+    ModuleAlreadyInstalled(ModuleType, Address, Address),
+
+    #[error("The {0} module {1:?} is not installed on account {2:?}")]
+    ModuleNotInstalled(ModuleType, Address, Address),
+
+    #[error("User operation {:?} reverted: {1}", .0.0)]
+    UserOperationReverted(UserOperationHash, String),
+
+    #[error("No receipt for user operation {:?} after {1:?}", .0.0)]
+    ReceiptTimeout(UserOperationHash, Duration),
+
+    #[error("No verifying paymaster is configured")]
+    VerifyingPaymasterNotConfigured,
+
+    #[error("Entry point {0:?} is not supported")]
+    UnsupportedEntryPoint(Address),
+
+    #[error("Chain id {0} is not supported")]
+    UnsupportedChain(u64),
 
     #[error("Unknown error")]
     UnknownError,
 }
 
+impl<M: Middleware> UserOpMiddlewareError<M> {
+    /// Typed error for a JSON-RPC error of the bundler, by ERC-4337 error code and `AAxx` reason.
+    pub fn from_rpc_error(error: JsonRpcError) -> Self {
+        let rejection = BundlerRejection::from(error);
+
+        if let Some(captures) = Regex::new(r"Call gas limit (\d+) is lower than call gas estimation (\d+)")
+            .unwrap()
+            .captures(&rejection.message)
+        {
+            if let (Ok(limit), Ok(estimation)) = (captures[1].parse(), captures[2].parse()) {
+                return UserOpMiddlewareError::CallGasLimitError(limit, estimation);
+            }
+        }
+        if let Some(captures) = Regex::new(r"Pre-verification gas (\d+) is lower than calculated pre-verification gas (\d+)")
+            .unwrap()
+            .captures(&rejection.message)
+        {
+            if let (Ok(provided), Ok(calculated)) = (captures[1].parse(), captures[2].parse()) {
+                return UserOpMiddlewareError::PreVerificationGasError(provided, calculated);
+            }
+        }
+        if matches!(rejection.aa_error, Some(AaError::AccountOverVerificationGasLimit | AaError::OverVerificationGasLimit)) {
+            return UserOpMiddlewareError::VerificationGasLimitError(rejection);
+        }
+
+        match rejection.code {
+            ERC4337_REJECTED_BY_ENTRY_POINT => UserOpMiddlewareError::EntryPointRejected(rejection),
+            ERC4337_REJECTED_BY_PAYMASTER => UserOpMiddlewareError::PaymasterRejected(rejection),
+            ERC4337_BANNED_OPCODE => UserOpMiddlewareError::BannedOpcode(rejection),
+            ERC4337_OUT_OF_TIME_RANGE => UserOpMiddlewareError::OutOfTimeRange(rejection),
+            ERC4337_THROTTLED_OR_BANNED => UserOpMiddlewareError::EntityThrottled(rejection),
+            ERC4337_STAKE_TOO_LOW => UserOpMiddlewareError::InsufficientStake(rejection),
+            ERC4337_UNSUPPORTED_AGGREGATOR => UserOpMiddlewareError::UnsupportedAggregator(rejection),
+            ERC4337_INVALID_SIGNATURE => UserOpMiddlewareError::InvalidSignature(rejection),
+            ERC4337_EXECUTION_REVERTED => UserOpMiddlewareError::ExecutionReverted(rejection),
+            INVALID_PARAMS => UserOpMiddlewareError::InvalidUserOperation(rejection),
+            _ => UserOpMiddlewareError::BundlerError(rejection),
+        }
+    }
+
+    /// The bundler's rejection behind this error, if it came from the bundler.
+    pub fn rejection(&self) -> Option<&BundlerRejection> {
+        match self {
+            UserOpMiddlewareError::VerificationGasLimitError(rejection)
+            | UserOpMiddlewareError::EntryPointRejected(rejection)
+            | UserOpMiddlewareError::PaymasterRejected(rejection)
+            | UserOpMiddlewareError::BannedOpcode(rejection)
+            | UserOpMiddlewareError::OutOfTimeRange(rejection)
+            | UserOpMiddlewareError::EntityThrottled(rejection)
+            | UserOpMiddlewareError::InsufficientStake(rejection)
+            | UserOpMiddlewareError::UnsupportedAggregator(rejection)
+            | UserOpMiddlewareError::InvalidSignature(rejection)
+            | UserOpMiddlewareError::ExecutionReverted(rejection)
+            | UserOpMiddlewareError::InvalidUserOperation(rejection)
+            | UserOpMiddlewareError::BundlerError(rejection) => Some(rejection),
+            _ => None,
+        }
+    }
+
+    /// Whether the bundler rejected the user operation's nonce, with AA25 or its own
+    /// invalid-nonce message.
+    pub fn is_nonce_error(&self) -> bool {
+        self.rejection().is_some_and(|rejection| {
+            rejection.aa_error == Some(AaError::InvalidAccountNonce)
+                || rejection.message.to_ascii_lowercase().contains("invalid nonce")
+        })
+    }
+
+    /// What resubmitting the user operation takes, `GiveUp` when it cannot succeed as is.
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            UserOpMiddlewareError::PreVerificationGasError(..)
+            | UserOpMiddlewareError::CallGasLimitError(..)
+            | UserOpMiddlewareError::VerificationGasLimitError(_) => Recovery::BumpGas,
+            UserOpMiddlewareError::InvalidSignature(_) | UserOpMiddlewareError::OutOfTimeRange(_) => Recovery::Resign,
+            _ => match self.rejection().and_then(|rejection| rejection.aa_error) {
+                Some(aa_error) => aa_error.recovery(),
+                None => Recovery::GiveUp,
+            },
+        }
+    }
+}
+
+/// ERC-4337 JSON-RPC error codes of `eth_sendUserOperation` and `eth_estimateUserOperationGas`.
+pub const ERC4337_REJECTED_BY_ENTRY_POINT: i64 = -32500;
+pub const ERC4337_REJECTED_BY_PAYMASTER: i64 = -32501;
+pub const ERC4337_BANNED_OPCODE: i64 = -32502;
+pub const ERC4337_OUT_OF_TIME_RANGE: i64 = -32503;
+pub const ERC4337_THROTTLED_OR_BANNED: i64 = -32504;
+pub const ERC4337_STAKE_TOO_LOW: i64 = -32505;
+pub const ERC4337_UNSUPPORTED_AGGREGATOR: i64 = -32506;
+pub const ERC4337_INVALID_SIGNATURE: i64 = -32507;
+pub const ERC4337_EXECUTION_REVERTED: i64 = -32521;
+pub const INVALID_PARAMS: i64 = -32602;
+
+/// How a rejected user operation can still go through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// Raise the gas limits, then sign again.
+    BumpGas,
+    /// Sign again, with a fresh nonce, time range or paymaster signature.
+    Resign,
+    /// Resubmitting the same operation fails again.
+    GiveUp,
+}
+
+/// JSON-RPC error of the bundler with the `AAxx` EntryPoint reason found in its message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BundlerRejection {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<serde_json::Value>,
+    pub aa_error: Option<AaError>,
+}
+
+impl From<JsonRpcError> for BundlerRejection {
+    fn from(error: JsonRpcError) -> Self {
+        Self {
+            code: error.code,
+            aa_error: AaError::from_message(&error.message),
+            message: error.message,
+            data: error.data,
+        }
+    }
+}
+
+impl fmt::Display for BundlerRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+/// `AAxx` revert reasons of the EntryPoint: 1x creation, 2x account, 3x paymaster, 4x
+/// verification gas, 5x post execution and 9x `handleOps` itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AaError {
+    SenderAlreadyConstructed,
+    InitCodeFailed,
+    InitCodeReturnedWrongSender,
+    InitCodeDidNotDeploySender,
+    AccountNotDeployed,
+    DidNotPayPrefund,
+    AccountExpiredOrNotDue,
+    AccountReverted,
+    AccountSignatureError,
+    InvalidAccountNonce,
+    AccountOverVerificationGasLimit,
+    PaymasterNotDeployed,
+    PaymasterDepositTooLow,
+    PaymasterExpiredOrNotDue,
+    PaymasterReverted,
+    PaymasterSignatureError,
+    PaymasterOverVerificationGasLimit,
+    OverVerificationGasLimit,
+    TooLittleVerificationGas,
+    PostOpReverted,
+    PrefundBelowActualGasCost,
+    InvalidBeneficiary,
+    FailedSendToBeneficiary,
+    InternalCallOnly,
+    InvalidPaymasterAndData,
+    GasValuesOverflow,
+    OutOfGas,
+    InvalidAggregator,
+    /// A reason this list does not know yet.
+    Other(u8),
+}
+
+impl AaError {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            10 => AaError::SenderAlreadyConstructed,
+            13 => AaError::InitCodeFailed,
+            14 => AaError::InitCodeReturnedWrongSender,
+            15 => AaError::InitCodeDidNotDeploySender,
+            20 => AaError::AccountNotDeployed,
+            21 => AaError::DidNotPayPrefund,
+            22 => AaError::AccountExpiredOrNotDue,
+            23 => AaError::AccountReverted,
+            24 => AaError::AccountSignatureError,
+            25 => AaError::InvalidAccountNonce,
+            26 => AaError::AccountOverVerificationGasLimit,
+            30 => AaError::PaymasterNotDeployed,
+            31 => AaError::PaymasterDepositTooLow,
+            32 => AaError::PaymasterExpiredOrNotDue,
+            33 => AaError::PaymasterReverted,
+            34 => AaError::PaymasterSignatureError,
+            36 => AaError::PaymasterOverVerificationGasLimit,
+            40 => AaError::OverVerificationGasLimit,
+            41 => AaError::TooLittleVerificationGas,
+            50 => AaError::PostOpReverted,
+            51 => AaError::PrefundBelowActualGasCost,
+            90 => AaError::InvalidBeneficiary,
+            91 => AaError::FailedSendToBeneficiary,
+            92 => AaError::InternalCallOnly,
+            93 => AaError::InvalidPaymasterAndData,
+            94 => AaError::GasValuesOverflow,
+            95 => AaError::OutOfGas,
+            96 => AaError::InvalidAggregator,
+            code => AaError::Other(code),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            AaError::SenderAlreadyConstructed => 10,
+            AaError::InitCodeFailed => 13,
+            AaError::InitCodeReturnedWrongSender => 14,
+            AaError::InitCodeDidNotDeploySender => 15,
+            AaError::AccountNotDeployed => 20,
+            AaError::DidNotPayPrefund => 21,
+            AaError::AccountExpiredOrNotDue => 22,
+            AaError::AccountReverted => 23,
+            AaError::AccountSignatureError => 24,
+            AaError::InvalidAccountNonce => 25,
+            AaError::AccountOverVerificationGasLimit => 26,
+            AaError::PaymasterNotDeployed => 30,
+            AaError::PaymasterDepositTooLow => 31,
+            AaError::PaymasterExpiredOrNotDue => 32,
+            AaError::PaymasterReverted => 33,
+            AaError::PaymasterSignatureError => 34,
+            AaError::PaymasterOverVerificationGasLimit => 36,
+            AaError::OverVerificationGasLimit => 40,
+            AaError::TooLittleVerificationGas => 41,
+            AaError::PostOpReverted => 50,
+            AaError::PrefundBelowActualGasCost => 51,
+            AaError::InvalidBeneficiary => 90,
+            AaError::FailedSendToBeneficiary => 91,
+            AaError::InternalCallOnly => 92,
+            AaError::InvalidPaymasterAndData => 93,
+            AaError::GasValuesOverflow => 94,
+            AaError::OutOfGas => 95,
+            AaError::InvalidAggregator => 96,
+            AaError::Other(code) => *code,
+        }
+    }
+
+    /// First `AAxx` code in a bundler error message, e.g. `"AA21 didn't pay prefund"`.
+    pub fn from_message(message: &str) -> Option<Self> {
+        message
+            .as_bytes()
+            .windows(4)
+            .find(|window| window.starts_with(b"AA") && window[2].is_ascii_digit() && window[3].is_ascii_digit())
+            .map(|window| AaError::from_code((window[2] - b'0') * 10 + (window[3] - b'0')))
+    }
+
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            AaError::InitCodeFailed
+            | AaError::AccountOverVerificationGasLimit
+            | AaError::PaymasterOverVerificationGasLimit
+            | AaError::OverVerificationGasLimit
+            | AaError::TooLittleVerificationGas
+            | AaError::OutOfGas => Recovery::BumpGas,
+            AaError::AccountExpiredOrNotDue
+            | AaError::AccountSignatureError
+            | AaError::InvalidAccountNonce
+            | AaError::PaymasterExpiredOrNotDue
+            | AaError::PaymasterSignatureError => Recovery::Resign,
+            _ => Recovery::GiveUp,
+        }
+    }
+}
+
+impl fmt::Display for AaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AA{:02}", self.code())
+    }
+}
+
 #[derive(Error, Clone, Debug)]
 pub enum UserOpBuilderError<M: Middleware> {
     
@@ -45,6 +388,156 @@ pub enum UserOpBuilderError<M: Middleware> {
     #[error("The field in the UserOperation is not set. Call the set_uo_{0} function to set")]
     MissingUserOperationField(String),
 
+    #[error("The paymaster did not quote a price for token {0:?}")]
+    TokenNotQuoted(Address),
+
+    #[error("The token paymaster data is a stub. Call fetch_token_paymaster_data to have it signed")]
+    TokenPaymasterDataNotSigned,
+
     #[error("Unknown error")]
     UnknownError,
-}
\ No newline at end of file
+}
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum ModeCodeError {
+
+    #[error("Unknown call type: {0:#04x}")]
+    UnknownCallType(u8),
+
+    #[error("Unknown exec type: {0:#04x}")]
+    UnknownExecType(u8),
+
+    #[error("Unused bytes of the mode code must be zero")]
+    NonZeroUnusedBytes,
+
+    #[error("Mode code must be 32 bytes")]
+    InvalidLength,
+
+    #[error("Mode code is not valid hex")]
+    InvalidHex,
+}
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[error("Unknown module type id: {0}")]
+pub struct ModuleTypeError(pub u8);
+
+/// A gas limit or fee of a user operation above the `uint128` the EntryPoint packs it into.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[error("{0} {1} does not fit in uint128")]
+pub struct Uint128Overflow(pub &'static str, pub U256);
+
+/// Why a sponsorship policy does not pay for a user operation.
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum SponsorshipRejection {
+    #[error("{0} {1} does not fit in uint128")]
+    GasLimitTooHigh(String, U256),
+
+    #[error("call data is not a decodable execute call: {0}")]
+    UndecodableCallData(String),
+
+    #[error("delegate calls are not sponsored")]
+    DelegateCall,
+
+    #[error("calls to {0:?} are not sponsored")]
+    TargetNotAllowed(Address),
+
+    #[error("function {1} of {0:?} is not sponsored")]
+    SelectorNotAllowed(Address, Bytes),
+
+    #[error("deployments through factory {0:?} are not sponsored")]
+    FactoryNotAllowed(Address),
+
+    #[error("max fee per gas {0} is above the sponsored {1}")]
+    MaxFeeTooHigh(U256, U256),
+
+    #[error("outside the sponsorship time windows")]
+    OutsideTimeWindow,
+
+    #[error("daily gas budget of {0:?} exceeded: {1} gas requested, {2} left")]
+    DailyGasBudgetExceeded(Address, U256, U256),
+}
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+#[error("Sponsorship rejected: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+pub struct SponsorshipRejected(pub Vec<SponsorshipRejection>);
+
+#[derive(Error, Debug)]
+pub enum BundlerClientError {
+    #[error("Bundler request failed: {0}")]
+    Transport(#[from] reqwest::Error),
+
+    #[error("Bundler answered with HTTP status {0}")]
+    Http(reqwest::StatusCode),
+
+    #[error("Invalid response to {0}: {1}")]
+    Decode(String, serde_json::Error),
+
+    #[error("Response id {1} does not match request id {0}")]
+    IdMismatch(u64, u64),
+
+    #[error("{0} returned no result")]
+    MissingResult(String),
+
+    #[error("Bundler error: {0}")]
+    Rpc(JsonRpcError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{Http, Provider};
+    use serde_json::json;
+
+    type Error = UserOpMiddlewareError<Provider<Http>>;
+
+    fn rpc_error(code: i64, message: &str) -> JsonRpcError {
+        JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: Some(json!({"paymaster": null})),
+        }
+    }
+
+    #[test]
+    fn maps_error_codes_and_aa_reasons() {
+        let error = Error::from_rpc_error(rpc_error(-32500, "validation reverted: AA21 didn't pay prefund"));
+        assert!(matches!(&error, Error::EntryPointRejected(rejection)
+            if rejection.aa_error == Some(AaError::DidNotPayPrefund) && rejection.data.is_some()));
+        assert_eq!(error.recovery(), Recovery::GiveUp);
+
+        let error = Error::from_rpc_error(rpc_error(-32500, "AA25 invalid account nonce"));
+        assert_eq!(error.recovery(), Recovery::Resign);
+        assert!(error.is_nonce_error());
+        assert!(Error::from_rpc_error(rpc_error(-32602, "Invalid nonce: expected 3")).is_nonce_error());
+        assert!(!Error::from_rpc_error(rpc_error(-32507, "bad sig")).is_nonce_error());
+
+        let error = Error::from_rpc_error(rpc_error(-32500, "AA40 over verificationGasLimit"));
+        assert!(matches!(error, Error::VerificationGasLimitError(_)));
+        assert_eq!(error.recovery(), Recovery::BumpGas);
+
+        assert!(matches!(Error::from_rpc_error(rpc_error(-32501, "AA33 reverted")), Error::PaymasterRejected(_)));
+        assert!(matches!(Error::from_rpc_error(rpc_error(-32507, "bad sig")), Error::InvalidSignature(_)));
+        assert!(matches!(Error::from_rpc_error(rpc_error(-32521, "reverted")), Error::ExecutionReverted(_)));
+        assert!(matches!(Error::from_rpc_error(rpc_error(-32000, "internal")), Error::BundlerError(_)));
+    }
+
+    #[test]
+    fn parses_gas_limits_from_messages() {
+        let error = Error::from_rpc_error(rpc_error(-32602, "Call gas limit 100 is lower than call gas estimation 250"));
+        assert!(matches!(error, Error::CallGasLimitError(100, 250)));
+
+        let error = Error::from_rpc_error(rpc_error(
+            -32602,
+            "Pre-verification gas 40000 is lower than calculated pre-verification gas 45000",
+        ));
+        assert!(matches!(error, Error::PreVerificationGasError(40000, 45000)));
+    }
+
+    #[test]
+    fn finds_aa_code_in_message() {
+        assert_eq!(AaError::from_message("FailedOp(0, AA95 out of gas)"), Some(AaError::OutOfGas));
+        assert_eq!(AaError::from_message("AA99 new reason"), Some(AaError::Other(99)));
+        assert_eq!(AaError::from_message("AAA invalid"), None);
+        assert_eq!(AaError::from_code(34).to_string(), "AA34");
+    }
+}
@@ -0,0 +1,245 @@
+use crate::bundler_client::BundlerClient;
+use async_trait::async_trait;
+use ethers::types::{FeeHistory, U256};
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Blocks of `eth_feeHistory` the priority fee is taken from.
+pub const FEE_HISTORY_BLOCKS: u64 = 10;
+
+/// Priority fee used when the recent blocks carry no transactions to learn from.
+pub const DEFAULT_PRIORITY_FEE_PER_GAS: u64 = 1_000_000_000;
+
+/// How aggressively fees are set, trading cost for inclusion speed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FeeStrategy {
+    Slow,
+    #[default]
+    Standard,
+    Fast,
+}
+
+impl FeeStrategy {
+    /// Percentile of the priority fees paid in recent blocks.
+    pub fn reward_percentile(&self) -> f64 {
+        match self {
+            FeeStrategy::Slow => 10.0,
+            FeeStrategy::Standard => 50.0,
+            FeeStrategy::Fast => 90.0,
+        }
+    }
+
+    /// Number of full blocks, each raising the base fee by 12.5%, that `max_fee_per_gas` still
+    /// covers.
+    pub fn base_fee_headroom_blocks(&self) -> u32 {
+        match self {
+            FeeStrategy::Slow => 1,
+            FeeStrategy::Standard => 3,
+            FeeStrategy::Fast => 6,
+        }
+    }
+}
+
+impl FromStr for FeeStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "slow" => Ok(FeeStrategy::Slow),
+            "standard" => Ok(FeeStrategy::Standard),
+            "fast" => Ok(FeeStrategy::Fast),
+            _ => Err(anyhow::anyhow!("unknown fee strategy {:?}, expected slow, standard or fast", s)),
+        }
+    }
+}
+
+impl fmt::Display for FeeStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FeeStrategy::Slow => "slow",
+            FeeStrategy::Standard => "standard",
+            FeeStrategy::Fast => "fast",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// EIP-1559 fees of a user operation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GasFees {
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+}
+
+/// Fee tiers some bundlers quote for user operations, e.g. `pimlico_getUserOperationGasPrice`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundlerGasPrice {
+    pub slow: GasFees,
+    pub standard: GasFees,
+    pub fast: GasFees,
+}
+
+impl BundlerGasPrice {
+    pub fn tier(&self, strategy: FeeStrategy) -> GasFees {
+        match strategy {
+            FeeStrategy::Slow => self.slow,
+            FeeStrategy::Standard => self.standard,
+            FeeStrategy::Fast => self.fast,
+        }
+    }
+}
+
+/// What a bundler quotes: complete fees, or only the priority fee to add to the node's base fee.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BundlerFeeQuote {
+    Fees(GasFees),
+    PriorityFee(U256),
+}
+
+/// Vendor specific JSON-RPC method of a bundler quoting user operation fees.
+#[async_trait]
+pub trait BundlerFeeSource: fmt::Debug + Send + Sync {
+    async fn quote(&self, bundler: &BundlerClient, strategy: FeeStrategy) -> anyhow::Result<BundlerFeeQuote>;
+}
+
+/// Pimlico's `pimlico_getUserOperationGasPrice`, the tier matching the strategy.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PimlicoFeeSource;
+
+#[async_trait]
+impl BundlerFeeSource for PimlicoFeeSource {
+    async fn quote(&self, bundler: &BundlerClient, strategy: FeeStrategy) -> anyhow::Result<BundlerFeeQuote> {
+        let price: BundlerGasPrice = bundler.call("pimlico_getUserOperationGasPrice", json!([])).await?;
+        Ok(BundlerFeeQuote::Fees(price.tier(strategy)))
+    }
+}
+
+/// Rundler's (Alchemy) `rundler_maxPriorityFeePerGas`, the lowest priority fee it accepts.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RundlerFeeSource;
+
+#[async_trait]
+impl BundlerFeeSource for RundlerFeeSource {
+    async fn quote(&self, bundler: &BundlerClient, _strategy: FeeStrategy) -> anyhow::Result<BundlerFeeQuote> {
+        let priority_fee: U256 = bundler.call("rundler_maxPriorityFeePerGas", json!([])).await?;
+        Ok(BundlerFeeQuote::PriorityFee(priority_fee))
+    }
+}
+
+/// Fee source by vendor name, `None` for `node`, the generic estimation.
+pub fn bundler_fee_source(name: &str) -> anyhow::Result<Option<Arc<dyn BundlerFeeSource>>> {
+    match name.to_ascii_lowercase().as_str() {
+        "pimlico" => Ok(Some(Arc::new(PimlicoFeeSource))),
+        "rundler" | "alchemy" => Ok(Some(Arc::new(RundlerFeeSource))),
+        "node" | "none" | "" => Ok(None),
+        _ => Err(anyhow::anyhow!("unknown bundler fee source {:?}, expected pimlico, rundler or node", name)),
+    }
+}
+
+/// Fees for a priority fee quoted by the bundler on top of the node's next base fee.
+pub fn fees_with_priority_fee(next_base_fee: U256, max_priority_fee_per_gas: U256, strategy: FeeStrategy) -> GasFees {
+    GasFees {
+        max_fee_per_gas: project_base_fee(next_base_fee, strategy.base_fee_headroom_blocks())
+            + max_priority_fee_per_gas,
+        max_priority_fee_per_gas,
+    }
+}
+
+/// Highest base fee reachable after `blocks` full blocks, rounding up each 12.5% step.
+pub fn project_base_fee(base_fee: U256, blocks: u32) -> U256 {
+    (0..blocks).fold(base_fee, |base_fee, _| (base_fee * 9 + 7) / 8)
+}
+
+/// Fees from `eth_feeHistory` queried with the strategy's reward percentile: the median of the
+/// recent priority fees on top of the projected base fee of the next block.
+pub fn fees_from_history(history: &FeeHistory, strategy: FeeStrategy) -> anyhow::Result<GasFees> {
+    // The last base fee is the one of the block after the newest in the history.
+    let next_base_fee = *history
+        .base_fee_per_gas
+        .last()
+        .ok_or_else(|| anyhow::anyhow!("fee history has no base fee"))?;
+
+    // Empty blocks report a reward of zero, which says nothing about what gets included.
+    let mut rewards = history
+        .reward
+        .iter()
+        .zip(history.gas_used_ratio.iter())
+        .filter(|(_, gas_used_ratio)| **gas_used_ratio > 0.0)
+        .filter_map(|(reward, _)| reward.first().copied())
+        .collect::<Vec<_>>();
+    rewards.sort();
+    let max_priority_fee_per_gas = match rewards.get(rewards.len() / 2) {
+        Some(reward) => *reward,
+        None => U256::from(DEFAULT_PRIORITY_FEE_PER_GAS),
+    };
+
+    Ok(fees_with_priority_fee(next_base_fee, max_priority_fee_per_gas, strategy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(base_fees: &[u64], gas_used_ratio: &[f64], rewards: &[u64]) -> FeeHistory {
+        FeeHistory {
+            base_fee_per_gas: base_fees.iter().map(|fee| U256::from(*fee)).collect(),
+            gas_used_ratio: gas_used_ratio.to_vec(),
+            oldest_block: U256::from(100u64),
+            reward: rewards.iter().map(|reward| vec![U256::from(*reward)]).collect(),
+        }
+    }
+
+    #[test]
+    fn projects_base_fee_and_takes_median_reward() {
+        let history = history(&[90, 95, 100, 800], &[0.5, 0.0, 0.9], &[30, 0, 10]);
+        let fees = fees_from_history(&history, FeeStrategy::Slow).unwrap();
+        // The empty block is ignored; the median of [10, 30] is the upper one.
+        assert_eq!(fees.max_priority_fee_per_gas, U256::from(30u64));
+        assert_eq!(fees.max_fee_per_gas, U256::from(900u64 + 30));
+
+        let fast = fees_from_history(&history, FeeStrategy::Fast).unwrap();
+        assert_eq!(fast.max_fee_per_gas, project_base_fee(U256::from(800u64), 6) + 30);
+        assert!(fast.max_fee_per_gas > fees.max_fee_per_gas);
+    }
+
+    #[test]
+    fn falls_back_to_default_priority_fee_without_transactions() {
+        let idle = history(&[100, 100], &[0.0], &[0]);
+        let fees = fees_from_history(&idle, FeeStrategy::Standard).unwrap();
+        assert_eq!(fees.max_priority_fee_per_gas, U256::from(DEFAULT_PRIORITY_FEE_PER_GAS));
+
+        assert!(fees_from_history(&history(&[], &[], &[]), FeeStrategy::Standard).is_err());
+    }
+
+    #[test]
+    fn rounds_base_fee_projection_up() {
+        assert_eq!(project_base_fee(U256::from(1u64), 1), U256::from(2u64));
+        assert_eq!(project_base_fee(U256::from(8u64), 2), U256::from(11u64));
+        assert_eq!(project_base_fee(U256::from(8u64), 0), U256::from(8u64));
+    }
+
+    #[test]
+    fn parses_bundler_gas_price_tiers() {
+        let price: BundlerGasPrice = serde_json::from_value(serde_json::json!({
+            "slow": {"maxFeePerGas": "0x1", "maxPriorityFeePerGas": "0x1"},
+            "standard": {"maxFeePerGas": "0x2", "maxPriorityFeePerGas": "0x1"},
+            "fast": {"maxFeePerGas": "0x3", "maxPriorityFeePerGas": "0x2"},
+        }))
+        .unwrap();
+        assert_eq!(price.tier(FeeStrategy::Fast).max_fee_per_gas, U256::from(3u64));
+        assert_eq!("FAST".parse::<FeeStrategy>().unwrap(), FeeStrategy::Fast);
+    }
+
+    #[test]
+    fn picks_fee_source_by_name() {
+        assert!(bundler_fee_source("Pimlico").unwrap().is_some());
+        assert!(bundler_fee_source("node").unwrap().is_none());
+        assert!(bundler_fee_source("stackup").is_err());
+    }
+}
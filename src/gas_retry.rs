@@ -0,0 +1,100 @@
+use crate::errors::UserOpMiddlewareError;
+use crate::primitives::user_operation::UserOperationPartial;
+use ethers::{providers::Middleware, types::U256};
+
+/// How often and by how much gas limits the bundler rejected as too low are raised before the
+/// user operation is signed and sent again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GasRetryPolicy {
+    /// Resubmissions after the first attempt; 0 disables retrying.
+    pub max_retries: u32,
+    /// Added on top of the limit the bundler asked for, in percent.
+    pub margin_percent: u64,
+}
+
+impl Default for GasRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            margin_percent: 10,
+        }
+    }
+}
+
+impl GasRetryPolicy {
+    pub fn new(max_retries: u32, margin_percent: u64) -> Self {
+        Self {
+            max_retries,
+            margin_percent,
+        }
+    }
+
+    /// `limit` plus the margin, rounded up.
+    pub fn with_margin(&self, limit: U256) -> U256 {
+        let raised = limit * (100 + self.margin_percent);
+        (raised + 99) / 100
+    }
+
+    /// Raises the limit `error` complains about. Returns `false` when the error is not about a
+    /// gas limit, so retrying would not help.
+    ///
+    /// Bundlers report the required call and pre-verification gas; for the verification gas
+    /// limit only the rejection is known, so the current limit is raised by the margin.
+    pub fn bump<M: Middleware>(
+        &self,
+        user_operation: &mut UserOperationPartial,
+        error: &UserOpMiddlewareError<M>,
+    ) -> bool {
+        match error {
+            UserOpMiddlewareError::CallGasLimitError(_, required) => {
+                user_operation.call_gas_limit = Some(self.with_margin(U256::from(*required)));
+            },
+            UserOpMiddlewareError::PreVerificationGasError(_, required) => {
+                user_operation.pre_verification_gas = Some(self.with_margin(U256::from(*required)));
+            },
+            UserOpMiddlewareError::VerificationGasLimitError(_) => {
+                let limit = user_operation.verification_gas_limit.unwrap_or_default();
+                // A zero margin would resubmit the same limit.
+                let raised = self.with_margin(limit).max(limit + 1);
+                user_operation.verification_gas_limit = Some(raised);
+            },
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundler_client::JsonRpcError;
+    use ethers::providers::{Http, Provider};
+
+    type Error = UserOpMiddlewareError<Provider<Http>>;
+
+    #[test]
+    fn bumps_the_rejected_limit_with_margin() {
+        let policy = GasRetryPolicy::default();
+        let mut user_operation = UserOperationPartial {
+            call_gas_limit: Some(U256::from(100u64)),
+            verification_gas_limit: Some(U256::from(1000u64)),
+            ..Default::default()
+        };
+
+        assert!(policy.bump(&mut user_operation, &Error::CallGasLimitError(100, 201)));
+        assert_eq!(user_operation.call_gas_limit, Some(U256::from(222u64)));
+
+        assert!(policy.bump(&mut user_operation, &Error::PreVerificationGasError(10, 50_000)));
+        assert_eq!(user_operation.pre_verification_gas, Some(U256::from(55_000u64)));
+
+        let over_limit = Error::from_rpc_error(JsonRpcError {
+            code: -32500,
+            message: "AA40 over verificationGasLimit".to_string(),
+            data: None,
+        });
+        assert!(policy.bump(&mut user_operation, &over_limit));
+        assert_eq!(user_operation.verification_gas_limit, Some(U256::from(1100u64)));
+
+        assert!(!policy.bump(&mut user_operation, &Error::UnknownError));
+    }
+}
@@ -1,4 +1,6 @@
 use crate::traits::{SmartWalletAccount, SmartWalletAccountFactory, MSABasicFactory};
+use crate::primitives::execution::{encode_batch, Execution};
+use crate::primitives::mode_code::ModeCode;
 use alloy::{
     primitives::{Address as a_Address, U256 as a_U256},
     sol,
@@ -17,6 +19,7 @@ abigen!(MSAFactory, "src/abi/MSAFactory.json",);
 abigen!(SimpleAccount, "src/abi/SimpleAccount.json",);
 abigen!(MSABasic, "src/abi/MSABasic.json",);
 abigen!(EntryPoint, "src/abi/EntryPoint.json",);
+abigen!(Bootstrap, "src/abi/Bootstrap.json",);
 
 sol! {function execute(address dest, uint256 value, bytes calldata func);}
 pub struct SimpleAccountExecute(executeCall);
@@ -76,6 +79,10 @@ impl<M: Middleware + 'static> MSABasicFactory<M> for MSAFactory<M> {
         self.get_address(salt.into(), init_code)
     }
 
+    fn implementation(&self) -> FunctionCall<Arc<M>, M, Address> {
+        self.implementation()
+    }
+
     fn clone_box(&self) -> Box<dyn MSABasicFactory<M>> {
         Box::new(self.clone())
     }
@@ -88,6 +95,24 @@ impl<M: Middleware + 'static> SmartWalletAccount for SimpleAccount<M> {
         sae.0.abi_encode()
     }
 
+    /// One call goes through `execute`, more through `executeBatch`, which cannot send value.
+    fn calldata_gen_executions(&self, executions: &[Execution]) -> anyhow::Result<Bytes> {
+        match executions {
+            [] => Err(anyhow::anyhow!("no execution given")),
+            [execution] => {
+                Ok(self.encode("execute", (execution.target, execution.value, execution.call_data.clone()))?)
+            },
+            _ => {
+                if executions.iter().any(|execution| !execution.value.is_zero()) {
+                    return Err(anyhow::anyhow!("SimpleAccount.executeBatch cannot send value"));
+                }
+                let targets: Vec<Address> = executions.iter().map(|execution| execution.target).collect();
+                let funcs: Vec<Bytes> = executions.iter().map(|execution| execution.call_data.clone()).collect();
+                Ok(self.encode("executeBatch", (targets, funcs))?)
+            },
+        }
+    }
+
     fn clone_box(&self) -> Box<dyn SmartWalletAccount> {
         Box::new(self.clone())
     }
@@ -100,8 +125,24 @@ impl<M: Middleware + 'static> SmartWalletAccount for MSABasic<M> {
         exec.0.abi_encode()
     }
 
+    /// Uses the single call type for one execution and the batch call type otherwise.
+    fn calldata_gen_executions(&self, executions: &[Execution]) -> anyhow::Result<Bytes> {
+        match executions {
+            [] => Err(anyhow::anyhow!("no execution given")),
+            [execution] => self.calldata_gen_with_mode(ModeCode::single(), execution.encode_single()),
+            _ => self.calldata_gen_with_mode(ModeCode::batch(), encode_batch(executions)),
+        }
+    }
+
     fn clone_box(&self) -> Box<dyn SmartWalletAccount> {
         Box::new(self.clone())
     }
 
 }
+
+impl<M: Middleware + 'static> MSABasic<M> {
+    /// Encodes `execute(mode, executionCalldata)` of the account.
+    pub fn calldata_gen_with_mode(&self, mode: ModeCode, execution_calldata: Bytes) -> anyhow::Result<Bytes> {
+        Ok(self.encode("execute", (mode.encode(), execution_calldata))?)
+    }
+}
@@ -0,0 +1,20 @@
+pub mod uo_builder;
+pub mod gen;
+pub mod errors;
+pub mod types;
+pub mod consts;
+pub mod traits;
+pub mod primitives;
+pub mod userop_middleware;
+pub mod bundler_client;
+pub mod gas_retry;
+pub mod paymaster;
+pub mod verifying_paymaster;
+pub mod signing;
+pub mod sponsorship;
+pub mod token_paymaster;
+pub mod contract_call;
+pub mod erc20;
+pub mod fee_oracle;
+pub mod nonce_manager;
+pub mod server;
@@ -1,42 +1,28 @@
 use ethers::{
     signers::{LocalWallet, Signer},
-    providers::{Provider, Http},
-    types::{Bytes, U256, Address, H256},
-    prelude::{abigen},
-};
-use std::{
-    env,
-    sync::Arc
+    providers::{Middleware, Provider, Http},
+    types::{Address, H256},
 };
+use std::env;
+use std::sync::Arc;
 use anyhow::Result;
 
-mod uo_builder;
-mod gen;
-mod errors;
-mod types;
-mod consts;
-mod traits;
-mod primitives;
-mod userop_middleware;
-// mod ERC7579Calldata;
-use primitives::user_operation::{UserOperation, UserOperationPartial};
-use userop_middleware::UserOpMiddleware;
 use dotenv::dotenv;
-use crate::consts::{ENTRY_POINT_SEPOLIA_V7,};
-use serde::{Deserialize, Serialize};
-use std::error::Error;
-use serde_json::Value;
-
-use crate::userop_middleware::JsonRpcResponse;
-
-abigen!(
-    Bootstrap,
-    "./src/abi/Bootstrap.json"
-);
-abigen!(
-    WalletContract, 
-    "./src/abi/MSABasic.json"
-);
+use erc7579_useroperation_server::{
+    consts::ENTRY_POINT_SEPOLIA_V7,
+    fee_oracle::{self, FeeStrategy},
+    gas_retry::GasRetryPolicy,
+    gen::MSAFactory,
+    paymaster::PaymasterClient,
+    primitives::{account_address::AccountAddressDeriver, account_init::AccountInit},
+    server,
+    signing::{SigningStrategies, SigningStrategy},
+    userop_middleware::UserOpMiddleware,
+    sponsorship::SponsorshipPolicy,
+    verifying_paymaster::VerifyingPaymaster,
+};
+
+const DEFAULT_SERVER_ADDRESS: &str = "127.0.0.1:3000";
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -47,14 +33,124 @@ async fn main() -> Result<()> {
 
     let rpc_url = env::var("SEPOLIA_RPC_ENDPOINT").expect("SEPOLIA_RPC_ENDPOINT not found");
     let provider =  Provider::try_from(rpc_url.clone())?;
-    let bundler_rpc_url = env::var("PIMLICO_SEPOLIA_ENDPOINT").expect("SEPOLIA_RPC_ENDPOINT not found");
+    // The user operation hash commits to the chain id, so the wallet must carry the node's one.
+    let chain_id = provider.get_chainid().await?.as_u64();
+    let wallet = wallet.with_chain_id(chain_id);
+    let bundler_rpc_url = env::var("PIMLICO_SEPOLIA_ENDPOINT").expect("PIMLICO_SEPOLIA_ENDPOINT not found");
 
-    let sender:Address = env::var("SENDER_ADDRESS").expect("SENDER_ADDRESS not found").parse()?;
     let validator:Address = env::var("VALIDATOR_ADDRESS").expect("VALIDATOR_ADDRESS not found").parse()?;
     let factory :Address = env::var("FACTORY_ADDRESS").expect("FACTORY_ADDRESS not found").parse()?;
     let bootstrap:Address= env::var("BOOTSTRAP_ADDRESS").expect("BOOTSTRAP_ADDRESS not found").parse()?;
 
-    let mut uo_middleware: UserOpMiddleware<Provider<Http>> = UserOpMiddleware::new(
+    // Salt the account is created with by the factory on its first user operation.
+    let account_salt: H256 = match env::var("ACCOUNT_SALT") {
+        Ok(salt) => salt.parse()?,
+        Err(_) => H256::zero(),
+    };
+    let account_init = AccountInit::with_validator(validator);
+
+    // Without SENDER_ADDRESS, use the account the factory deploys for ACCOUNT_SALT.
+    let sender: Address = match env::var("SENDER_ADDRESS") {
+        Ok(sender) => sender.parse()?,
+        Err(_) => {
+            let implementation = MSAFactory::new(factory, Arc::new(provider.clone()))
+                .implementation()
+                .call()
+                .await?;
+            AccountAddressDeriver::new(factory, implementation)
+                .address(account_salt, &account_init.init_code(bootstrap))
+        },
+    };
+
+    // Independent nonce sequences user operations are spread over.
+    let nonce_lanes: u32 = match env::var("NONCE_LANES") {
+        Ok(lanes) => lanes.parse()?,
+        Err(_) => 1,
+    };
+
+    // slow, standard or fast
+    let fee_strategy: FeeStrategy = match env::var("FEE_STRATEGY") {
+        Ok(strategy) => strategy.parse()?,
+        Err(_) => FeeStrategy::default(),
+    };
+
+    // pimlico, rundler or node; the bundler's own fee quote is preferred over the node's estimate.
+    let bundler_fee_source = match env::var("BUNDLER_FEE_SOURCE") {
+        Ok(source) if !source.is_empty() => fee_oracle::bundler_fee_source(&source)?,
+        _ if bundler_rpc_url.contains("pimlico") => fee_oracle::bundler_fee_source("pimlico")?,
+        _ => None,
+    };
+
+    // Resubmissions after the bundler rejects a gas limit, raised to its estimate plus a margin.
+    let gas_retry_limit: u32 = match env::var("GAS_RETRY_LIMIT") {
+        Ok(limit) => limit.parse()?,
+        Err(_) => GasRetryPolicy::default().max_retries,
+    };
+    let gas_retry_margin: u64 = match env::var("GAS_RETRY_MARGIN_PERCENT") {
+        Ok(margin) => margin.parse()?,
+        Err(_) => GasRetryPolicy::default().margin_percent,
+    };
+
+    // ERC-7677 paymaster service sponsoring the built user operations, with the context it
+    // expects, e.g. {"sponsorshipPolicyId":"sp_..."}.
+    let paymaster = match env::var("PAYMASTER_URL") {
+        Ok(url) if !url.is_empty() => {
+            let context = match env::var("PAYMASTER_CONTEXT") {
+                Ok(context) if !context.is_empty() => serde_json::from_str(&context)?,
+                _ => serde_json::Value::Null,
+            };
+            Some(PaymasterClient::new(url, context))
+        },
+        _ => None,
+    };
+
+    // Our own VerifyingPaymaster, signed for with a key of its own, served as pm_* methods.
+    let verifying_paymaster = match env::var("VERIFYING_PAYMASTER_ADDRESS") {
+        Ok(address) if !address.is_empty() => {
+            let signer: LocalWallet = env::var("VERIFYING_PAYMASTER_SIGNER_KEY")
+                .expect("VERIFYING_PAYMASTER_SIGNER_KEY not found")
+                .parse()
+                .expect("Invalid paymaster signer key");
+            // JSON rules of what is sponsored, see SponsorshipPolicy. pm_* methods are served to
+            // anyone, so the paymaster never signs without one; `{}` sponsors everything.
+            let policy_file = env::var("SPONSORSHIP_POLICY_FILE")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .expect("SPONSORSHIP_POLICY_FILE not found, it is required with VERIFYING_PAYMASTER_ADDRESS");
+            let mut policy: SponsorshipPolicy = serde_json::from_str(&std::fs::read_to_string(policy_file)?)?;
+            if let Ok(seconds) = env::var("PAYMASTER_VALIDITY_SECONDS") {
+                policy.validity_seconds = seconds.parse()?;
+            }
+            if let Some(name) = env::var("PAYMASTER_SPONSOR_NAME").ok().filter(|name| !name.is_empty()) {
+                policy.sponsor_name = Some(name);
+            }
+            Some(VerifyingPaymaster::new(address.parse()?, signer, chain_id).with_policy(policy))
+        },
+        _ => None,
+    };
+
+    // rawHash, eip191 or eip712: what the wallet signs, as the validator module expects it.
+    let signing_strategy: SigningStrategy = match env::var("SIGNING_STRATEGY") {
+        Ok(strategy) if !strategy.is_empty() => strategy.parse()?,
+        _ => SigningStrategy::default(),
+    };
+    // Validators verifying differently, as validator=strategy pairs, e.g. 0x...=rawHash,0x...=eip712.
+    let signing = match env::var("VALIDATOR_SIGNING_STRATEGIES") {
+        Ok(list) => SigningStrategies::new(signing_strategy).with_validators(&list)?,
+        Err(_) => SigningStrategies::new(signing_strategy),
+    };
+    // EIP-712 domains of validators not using the v0.8 EntryPoint's, as
+    // validator=name:version[:verifyingContract] pairs.
+    let signing = match env::var("VALIDATOR_EIP712_DOMAINS") {
+        Ok(list) => signing.with_eip712_domains(&list)?,
+        Err(_) => signing,
+    };
+
+    let rpc_signing_token = env::var("RPC_SIGNING_TOKEN").ok();
+
+    let server_address = env::var("SERVER_ADDRESS").unwrap_or_else(|_| DEFAULT_SERVER_ADDRESS.to_string());
+
+    let uo_middleware: UserOpMiddleware<Provider<Http>> = UserOpMiddleware::new(
         provider.clone(),
         ENTRY_POINT_SEPOLIA_V7.parse::<Address>().unwrap(),
         bundler_rpc_url,
@@ -63,16 +159,16 @@ async fn main() -> Result<()> {
         validator,
         factory,
         bootstrap
-    );
-
-    let to_address: Address = "0xc0c374f049f2e0036B48D93346038f0133B8f00F".parse()?;
-    let value = U256::from(1000000000000000u64);
-
-    let user_operation = uo_middleware.uogen_send_eth(to_address, value).await?;
-
-    // send user operation
-    let sent = uo_middleware.send_user_operation(&user_operation).await?;
+    )
+    .with_account_init(account_salt, account_init)
+    .with_nonce_lanes(nonce_lanes)?
+    .with_fee_strategy(fee_strategy)
+    .with_bundler_fee_source(bundler_fee_source)
+    .with_gas_retry(GasRetryPolicy::new(gas_retry_limit, gas_retry_margin))
+    .with_paymaster(paymaster)
+    .with_verifying_paymaster(verifying_paymaster)
+    .with_signing_strategies(signing)
+    .with_rpc_signing_token(rpc_signing_token);
 
-    println!("send! : {:?}", sent);
-    Ok(())
+    server::serve(server_address, uo_middleware).await
 }
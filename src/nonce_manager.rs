@@ -0,0 +1,287 @@
+use ethers::types::{Address, U256};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// 192-bit EntryPoint nonce key, the upper bits of the user operation nonce.
+///
+/// ERC-7579 accounts read the validator from the top 20 bytes of the nonce, so a key is usually
+/// `validator ++ lane`, where the 4-byte lane lets one validator use independent sequences.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "U256", into = "U256")]
+pub struct NonceKey(U256);
+
+impl NonceKey {
+    pub const BITS: usize = 192;
+
+    pub fn new(key: U256) -> anyhow::Result<Self> {
+        if key.bits() > Self::BITS {
+            return Err(anyhow::anyhow!("nonce key {:#x} does not fit in 192 bits", key));
+        }
+        Ok(Self(key))
+    }
+
+    /// Key selecting `validator` on the default lane.
+    pub fn from_validator(validator: Address) -> Self {
+        Self::from_validator_lane(validator, 0)
+    }
+
+    pub fn from_validator_lane(validator: Address, lane: u32) -> Self {
+        let mut key = [0u8; 32];
+        key[8..28].copy_from_slice(validator.as_bytes());
+        key[28..].copy_from_slice(&lane.to_be_bytes());
+        Self(U256::from_big_endian(&key))
+    }
+
+    pub fn validator(&self) -> Address {
+        let key = self.to_bytes();
+        Address::from_slice(&key[8..28])
+    }
+
+    pub fn lane(&self) -> u32 {
+        let key = self.to_bytes();
+        u32::from_be_bytes([key[28], key[29], key[30], key[31]])
+    }
+
+    /// Same validator bits with another lane.
+    pub fn with_lane(&self, lane: u32) -> Self {
+        Self::from_validator_lane(self.validator(), lane)
+    }
+
+    /// Full nonce `key << 64 | sequence`.
+    pub fn nonce(&self, sequence: u64) -> U256 {
+        (self.0 << 64) | U256::from(sequence)
+    }
+
+    pub fn as_u256(&self) -> U256 {
+        self.0
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        self.0.to_big_endian(&mut key);
+        key
+    }
+}
+
+impl TryFrom<U256> for NonceKey {
+    type Error = anyhow::Error;
+
+    fn try_from(value: U256) -> Result<Self, Self::Error> {
+        Self::new(value)
+    }
+}
+
+impl From<NonceKey> for U256 {
+    fn from(value: NonceKey) -> Self {
+        value.0
+    }
+}
+
+impl fmt::Display for NonceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// Splits a user operation nonce into its key and 64-bit sequence.
+pub fn split_nonce(nonce: U256) -> (NonceKey, u64) {
+    (NonceKey(nonce >> 64), nonce.low_u64())
+}
+
+/// Hands out nonces per `(sender, key)` without asking the EntryPoint each time, so several user
+/// operations can be in flight on the same sequence. The cache holds the next nonce not handed
+/// out yet; building reserves a nonce, so operations built before any is sent get distinct ones.
+#[derive(Debug, Default)]
+pub struct NonceManager {
+    next: Mutex<HashMap<(Address, NonceKey), U256>>,
+}
+
+impl NonceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The next cached nonce, or `None` when the sequence has to be read from the chain.
+    pub fn peek(&self, sender: Address, key: NonceKey) -> Option<U256> {
+        self.next.lock().get(&(sender, key)).copied()
+    }
+
+    /// Takes the next cached nonce, or `None` when the sequence has to be read from the chain.
+    pub fn reserve(&self, sender: Address, key: NonceKey) -> Option<U256> {
+        let mut next = self.next.lock();
+        let nonce = next.get_mut(&(sender, key))?;
+        let reserved = *nonce;
+        *nonce = reserved + 1;
+        Some(reserved)
+    }
+
+    /// Takes a nonce given the on-chain one, keeping the cached nonce if another caller got
+    /// ahead while `on_chain` was fetched.
+    pub fn reserve_from(&self, sender: Address, key: NonceKey, on_chain: U256) -> U256 {
+        let mut next = self.next.lock();
+        let nonce = next.entry((sender, key)).or_insert(on_chain);
+        let reserved = (*nonce).max(on_chain);
+        *nonce = reserved + 1;
+        reserved
+    }
+
+    /// Gives `nonce` back if nothing was handed out after it, e.g. when the bundler rejected it.
+    pub fn release(&self, sender: Address, nonce: U256) {
+        let (key, _) = split_nonce(nonce);
+        if let Some(cached) = self.next.lock().get_mut(&(sender, key)) {
+            if *cached == nonce + 1 {
+                *cached = nonce;
+            }
+        }
+    }
+
+    /// Records that `nonce` was accepted by the bundler, so the sequence continues after it even
+    /// if it was not reserved here.
+    pub fn confirm(&self, sender: Address, nonce: U256) {
+        let (key, _) = split_nonce(nonce);
+        let mut next = self.next.lock();
+        let cached = next.entry((sender, key)).or_insert(nonce + 1);
+        *cached = (*cached).max(nonce + 1);
+    }
+
+    /// Moves the cached sequence up to the on-chain one. Nonces of operations still in the
+    /// mempool are not counted on chain, so a cache that is ahead is kept.
+    pub fn resync(&self, sender: Address, key: NonceKey, on_chain: U256) {
+        let mut next = self.next.lock();
+        let nonce = next.entry((sender, key)).or_insert(on_chain);
+        *nonce = (*nonce).max(on_chain);
+    }
+}
+
+/// Spreads user operations of one validator over `lanes` nonce keys, round robin, so operations
+/// on different lanes do not wait for each other in the bundler mempool. Every key keeps the
+/// validator bits of `base`; only the lane differs.
+#[derive(Debug)]
+pub struct NonceLanes {
+    base: NonceKey,
+    lanes: u32,
+    next: AtomicU32,
+}
+
+impl NonceLanes {
+    /// Lanes `base.lane() .. base.lane() + lanes`.
+    pub fn new(base: NonceKey, lanes: u32) -> anyhow::Result<Self> {
+        if lanes == 0 {
+            return Err(anyhow::anyhow!("at least one nonce lane is needed"));
+        }
+        if base.lane().checked_add(lanes - 1).is_none() {
+            return Err(anyhow::anyhow!("{} lanes from lane {} overflow the 32-bit lane", lanes, base.lane()));
+        }
+        Ok(Self {
+            base,
+            lanes,
+            next: AtomicU32::new(0),
+        })
+    }
+
+    pub fn lanes(&self) -> u32 {
+        self.lanes
+    }
+
+    /// Key of the `index`-th lane, wrapping around.
+    pub fn key(&self, index: u32) -> NonceKey {
+        self.base.with_lane(self.base.lane() + index % self.lanes)
+    }
+
+    pub fn keys(&self) -> Vec<NonceKey> {
+        (0..self.lanes).map(|index| self.key(index)).collect()
+    }
+
+    /// Key for the next independent user operation.
+    pub fn allocate(&self) -> NonceKey {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        self.key(index)
+    }
+
+    /// Keys for `count` operations that must not share a lane, taken in one step so concurrent
+    /// allocations cannot interleave with them. Fails when there are fewer lanes than `count`.
+    pub fn allocate_distinct(&self, count: u32) -> anyhow::Result<Vec<NonceKey>> {
+        if count > self.lanes {
+            return Err(anyhow::anyhow!("{} operations cannot use distinct lanes out of {}", count, self.lanes));
+        }
+        let first = u64::from(self.next.fetch_add(count, Ordering::Relaxed) % self.lanes);
+        let lanes = u64::from(self.lanes);
+        Ok((0..u64::from(count)).map(|offset| self.key(((first + offset) % lanes) as u32)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator() -> Address {
+        "0x503b54Ed1E62365F0c9e4caF1479623b08acbe77".parse().unwrap()
+    }
+
+    #[test]
+    fn puts_validator_in_top_bytes_of_nonce() {
+        let key = NonceKey::from_validator_lane(validator(), 7);
+        let nonce = key.nonce(3);
+
+        let mut bytes = [0u8; 32];
+        nonce.to_big_endian(&mut bytes);
+        assert_eq!(&bytes[..20], validator().as_bytes());
+        assert_eq!(bytes[20..24], [0, 0, 0, 7]);
+        assert_eq!(U256::from_big_endian(&bytes[24..]), U256::from(3u64));
+
+        assert_eq!(split_nonce(nonce), (key, 3));
+        assert_eq!(key.validator(), validator());
+        assert_eq!(key.lane(), 7);
+        assert_eq!(key.with_lane(0), NonceKey::from_validator(validator()));
+        assert!(NonceKey::new(U256::one() << 192).is_err());
+    }
+
+    #[test]
+    fn reserves_distinct_nonces_before_any_is_sent() {
+        let manager = NonceManager::new();
+        let sender = Address::from_low_u64_be(1);
+        let key = NonceKey::from_validator(validator());
+
+        assert_eq!(manager.reserve(sender, key), None);
+        assert_eq!(manager.reserve_from(sender, key, key.nonce(5)), key.nonce(5));
+        assert_eq!(manager.reserve(sender, key), Some(key.nonce(6)));
+
+        // An older nonce cannot be given back once a later one is out.
+        manager.release(sender, key.nonce(5));
+        assert_eq!(manager.peek(sender, key), Some(key.nonce(7)));
+        manager.release(sender, key.nonce(6));
+        assert_eq!(manager.peek(sender, key), Some(key.nonce(6)));
+
+        // Sending a nonce reserved elsewhere moves the sequence past it, never back.
+        manager.confirm(sender, key.nonce(8));
+        manager.confirm(sender, key.nonce(5));
+        assert_eq!(manager.peek(sender, key), Some(key.nonce(9)));
+
+        // A stale on-chain read does not move the sequence back behind pending operations.
+        assert_eq!(manager.reserve_from(sender, key, key.nonce(2)), key.nonce(9));
+        manager.resync(sender, key, key.nonce(4));
+        assert_eq!(manager.peek(sender, key), Some(key.nonce(10)));
+        manager.resync(sender, key, key.nonce(12));
+        assert_eq!(manager.peek(sender, key), Some(key.nonce(12)));
+    }
+
+    #[test]
+    fn allocates_lanes_round_robin_keeping_the_validator() {
+        let lanes = NonceLanes::new(NonceKey::from_validator_lane(validator(), 10), 3).unwrap();
+        let allocated = (0..4).map(|_| lanes.allocate()).collect::<Vec<_>>();
+        assert_eq!(allocated.iter().map(NonceKey::lane).collect::<Vec<_>>(), vec![10, 11, 12, 10]);
+        assert!(allocated.iter().all(|key| key.validator() == validator()));
+        assert_eq!(lanes.keys().len(), 3);
+
+        // Continues the round robin, and a wrap around keeps the lanes distinct.
+        let distinct = lanes.allocate_distinct(3).unwrap();
+        assert_eq!(distinct.iter().map(NonceKey::lane).collect::<Vec<_>>(), vec![11, 12, 10]);
+        assert!(lanes.allocate_distinct(4).is_err());
+
+        assert!(NonceLanes::new(NonceKey::from_validator(validator()), 0).is_err());
+        assert!(NonceLanes::new(NonceKey::from_validator_lane(validator(), u32::MAX), 2).is_err());
+    }
+}
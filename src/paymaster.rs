@@ -0,0 +1,180 @@
+use crate::bundler_client::BundlerClient;
+use crate::errors::BundlerClientError;
+use crate::primitives::user_operation::UserOperationPartial;
+use crate::token_paymaster::TokenQuote;
+use ethers::types::{Address, Bytes, U256, U64};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Who sponsors the user operation, for wallets to show.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymasterSponsor {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+/// Result of `pm_getPaymasterStubData`: paymaster fields good enough for gas estimation. When
+/// `is_final` is set they are also the ones to sign and `pm_getPaymasterData` is skipped.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymasterStubData {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sponsor: Option<PaymasterSponsor>,
+    pub paymaster: Address,
+    pub paymaster_data: Bytes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_verification_gas_limit: Option<U256>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paymaster_post_op_gas_limit: Option<U256>,
+    #[serde(default)]
+    pub is_final: bool,
+}
+
+impl PaymasterStubData {
+    /// Sets the paymaster, its data and the gas limits the service knows.
+    pub fn fill(&self, user_operation: &mut UserOperationPartial) {
+        user_operation.paymaster = Some(self.paymaster);
+        user_operation.paymaster_data = Some(self.paymaster_data.clone());
+        if self.paymaster_verification_gas_limit.is_some() {
+            user_operation.paymaster_verification_gas_limit = self.paymaster_verification_gas_limit;
+        }
+        if self.paymaster_post_op_gas_limit.is_some() {
+            user_operation.paymaster_post_op_gas_limit = self.paymaster_post_op_gas_limit;
+        }
+    }
+}
+
+/// Result of `pm_getPaymasterData`, the paymaster fields to sign.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymasterData {
+    pub paymaster: Address,
+    pub paymaster_data: Bytes,
+}
+
+impl PaymasterData {
+    pub fn fill(&self, user_operation: &mut UserOperationPartial) {
+        user_operation.paymaster = Some(self.paymaster);
+        user_operation.paymaster_data = Some(self.paymaster_data.clone());
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenQuotes {
+    quotes: Vec<TokenQuote>,
+}
+
+/// ERC-7677 paymaster web service client. `context` is passed unchanged with every request,
+/// e.g. `{"sponsorshipPolicyId": "..."}`; its format is up to the service.
+#[derive(Clone, Debug)]
+pub struct PaymasterClient {
+    rpc: BundlerClient,
+    pub context: Value,
+}
+
+impl PaymasterClient {
+    pub fn new(url: impl Into<String>, context: Value) -> Self {
+        Self {
+            rpc: BundlerClient::new(url),
+            context,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        self.rpc.url()
+    }
+
+    pub async fn get_paymaster_stub_data(
+        &self,
+        user_operation: &UserOperationPartial,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> Result<PaymasterStubData, BundlerClientError> {
+        self.rpc.call("pm_getPaymasterStubData", self.params(user_operation, entry_point, chain_id)).await
+    }
+
+    /// Called with the final gas limits and fees, which the paymaster signature commits to.
+    pub async fn get_paymaster_data(
+        &self,
+        user_operation: &UserOperationPartial,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> Result<PaymasterData, BundlerClientError> {
+        self.rpc.call("pm_getPaymasterData", self.params(user_operation, entry_point, chain_id)).await
+    }
+
+    /// `pm_getPaymasterData` for a token paymaster, with `token` added to the context so the
+    /// service signs ERC-20 mode data charging it.
+    pub async fn get_token_paymaster_data(
+        &self,
+        user_operation: &UserOperationPartial,
+        entry_point: Address,
+        chain_id: u64,
+        token: Address,
+    ) -> Result<PaymasterData, BundlerClientError> {
+        let mut params = self.params(user_operation, entry_point, chain_id);
+        params[3] = match &self.context {
+            Value::Object(context) => {
+                let mut context = context.clone();
+                context.insert("token".to_string(), json!(token));
+                Value::Object(context)
+            },
+            _ => json!({"token": token}),
+        };
+        self.rpc.call("pm_getPaymasterData", params).await
+    }
+
+    /// `pimlico_getTokenQuotes`: what gas costs in each of `tokens` at the service's ERC-20
+    /// paymaster. Tokens the paymaster does not accept are left out.
+    pub async fn get_token_quotes(
+        &self,
+        tokens: &[Address],
+        entry_point: Address,
+        chain_id: u64,
+    ) -> Result<Vec<TokenQuote>, BundlerClientError> {
+        let params = json!([{"tokens": tokens}, entry_point, U64::from(chain_id)]);
+        let quotes: TokenQuotes = self.rpc.call("pimlico_getTokenQuotes", params).await?;
+        Ok(quotes.quotes)
+    }
+
+    fn params(&self, user_operation: &UserOperationPartial, entry_point: Address, chain_id: u64) -> Value {
+        json!([user_operation, entry_point, U64::from(chain_id), self.context])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_stub_fields_keeping_unknown_limits() {
+        let stub: PaymasterStubData = serde_json::from_value(json!({
+            "sponsor": {"name": "Sponsor"},
+            "paymaster": "0x0000000000000039cd5e8aE05257CE51C473ddd1",
+            "paymasterData": "0xabcd",
+            "paymasterPostOpGasLimit": "0xc350",
+        }))
+        .unwrap();
+        assert!(!stub.is_final);
+
+        let mut user_operation = UserOperationPartial {
+            paymaster_verification_gas_limit: Some(U256::from(7u64)),
+            ..Default::default()
+        };
+        stub.fill(&mut user_operation);
+        assert_eq!(user_operation.paymaster, Some(stub.paymaster));
+        assert_eq!(user_operation.paymaster_data, Some(Bytes::from(vec![0xab, 0xcd])));
+        assert_eq!(user_operation.paymaster_verification_gas_limit, Some(U256::from(7u64)));
+        assert_eq!(user_operation.paymaster_post_op_gas_limit, Some(U256::from(50_000u64)));
+    }
+
+    #[test]
+    fn sends_chain_id_as_hex_and_context() {
+        let client = PaymasterClient::new("http://localhost:3001", json!({"policy": "free"}));
+        let params = client.params(&UserOperationPartial::default(), Address::zero(), 11155111);
+        assert_eq!(params[2], json!("0xaa36a7"));
+        assert_eq!(params[3], json!({"policy": "free"}));
+    }
+}
@@ -0,0 +1,133 @@
+use ethers::{
+    types::{Address, Bytes, H256},
+    utils::{get_create2_address_from_hash, keccak256},
+};
+
+/// Creation code of the Solady ERC-1967 minimal proxy (`LibClone.initCodeERC1967`) that
+/// `MSAFactory` deploys for each account, split around the implementation address.
+const ERC1967_PROXY_PREFIX: [u8; 9] = [0x60, 0x3d, 0x3d, 0x81, 0x60, 0x22, 0x3d, 0x39, 0x73];
+const ERC1967_PROXY_SUFFIX: [u8; 66] = [
+    0x60, 0x09,
+    0x51, 0x55, 0xf3, 0x36, 0x3d, 0x3d, 0x37, 0x3d, 0x3d, 0x36, 0x3d, 0x7f, 0x36, 0x08, 0x94, 0xa1,
+    0x3b, 0xa1, 0xa3, 0x21, 0x06, 0x67, 0xc8, 0x28, 0x49, 0x2d, 0xb9, 0x8d, 0xca, 0x3e, 0x20, 0x76,
+    0xcc, 0x37, 0x35, 0xa9, 0x20, 0xa3, 0xca, 0x50, 0x5d, 0x38, 0x2b, 0xbc, 0x54, 0x5a, 0xf4, 0x3d,
+    0x60, 0x00, 0x80, 0x3e, 0x60, 0x38, 0x57, 0x3d, 0x60, 0x00, 0xfd, 0x5b, 0x3d, 0x60, 0x00, 0xf3,
+];
+
+/// Init code of the ERC-1967 proxy pointing at `implementation`, 0x5f bytes.
+pub fn erc1967_proxy_init_code(implementation: Address) -> Bytes {
+    let mut init_code = Vec::with_capacity(0x5f);
+    init_code.extend_from_slice(&ERC1967_PROXY_PREFIX);
+    init_code.extend_from_slice(implementation.as_bytes());
+    init_code.extend_from_slice(&ERC1967_PROXY_SUFFIX);
+    init_code.into()
+}
+
+/// `MSAFactory._getSalt`: `keccak256(abi.encodePacked(salt, initCode))`, so the address commits
+/// to the modules the account is initialized with.
+pub fn msa_factory_salt(salt: H256, init_code: &[u8]) -> H256 {
+    let mut packed = Vec::with_capacity(32 + init_code.len());
+    packed.extend_from_slice(salt.as_bytes());
+    packed.extend_from_slice(init_code);
+    H256::from(keccak256(packed))
+}
+
+/// Derives `MSAFactory.getAddress(salt, initCode)` without a node. The proxy init code hash only
+/// depends on the implementation, so it is hashed once and every address costs two keccaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccountAddressDeriver {
+    pub factory: Address,
+    pub proxy_init_code_hash: H256,
+}
+
+impl AccountAddressDeriver {
+    /// `implementation` is `MSAFactory.implementation()`, fixed at the factory deployment.
+    pub fn new(factory: Address, implementation: Address) -> Self {
+        Self {
+            factory,
+            proxy_init_code_hash: H256::from(keccak256(erc1967_proxy_init_code(implementation))),
+        }
+    }
+
+    pub fn address(&self, salt: H256, init_code: &[u8]) -> Address {
+        get_create2_address_from_hash(
+            self.factory,
+            msa_factory_salt(salt, init_code),
+            self.proxy_init_code_hash,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::MSA_FACTORY_SEPOLIA;
+    use alloy::primitives::{keccak256 as a_keccak256, Address as a_Address};
+
+    fn factory() -> Address {
+        MSA_FACTORY_SEPOLIA.parse().unwrap()
+    }
+
+    fn implementation() -> Address {
+        "0x6f1E8B3fBf8C83EF0C19B0A3E1b4C0e0F3eA7B6A".parse().unwrap()
+    }
+
+    #[test]
+    fn proxy_init_code_matches_factory_bytecode() {
+        let init_code = erc1967_proxy_init_code(implementation());
+        assert_eq!(init_code.len(), 0x5f);
+        assert_eq!(&init_code[9..29], implementation().as_bytes());
+
+        // The factory assembles the same init code from these constants before hashing it.
+        let artifact: serde_json::Value =
+            serde_json::from_str(include_str!("../abi/MSAFactory.json")).unwrap();
+        let bytecode = artifact["deployedBytecode"]["object"].as_str().unwrap();
+        let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        assert!(bytecode.contains(&hex(&ERC1967_PROXY_PREFIX)));
+        assert!(bytecode.contains(&hex(&ERC1967_PROXY_SUFFIX[..2])));
+        assert!(bytecode.contains(&hex(&ERC1967_PROXY_SUFFIX[2..34])));
+        assert!(bytecode.contains(&hex(&ERC1967_PROXY_SUFFIX[34..])));
+    }
+
+    #[test]
+    fn derives_create2_address() {
+        let salt = H256::from_low_u64_be(1);
+        let init_code: Bytes = "0xdeadbeef".parse().unwrap();
+
+        let hashed_salt = a_keccak256([salt.as_bytes(), init_code.as_ref()].concat());
+        let expected = a_Address::from(factory().0).create2(
+            hashed_salt,
+            a_keccak256(erc1967_proxy_init_code(implementation())),
+        );
+
+        let deriver = AccountAddressDeriver::new(factory(), implementation());
+        assert_eq!(deriver.address(salt, &init_code).0, expected.0 .0);
+        assert_eq!(msa_factory_salt(salt, &init_code).0, hashed_salt.0);
+        assert_ne!(deriver.address(salt, &[]), deriver.address(salt, &init_code));
+    }
+
+    /// Compares with `MSAFactory.getAddress` on Sepolia; needs `SEPOLIA_RPC_ENDPOINT`.
+    #[tokio::test]
+    #[ignore]
+    async fn matches_on_chain_get_address() {
+        use crate::gen::MSAFactory;
+        use crate::primitives::account_init::AccountInit;
+        use ethers::providers::{Http, Provider};
+        use std::sync::Arc;
+
+        dotenv::dotenv().ok();
+        let rpc_url = std::env::var("SEPOLIA_RPC_ENDPOINT").unwrap();
+        let provider = Arc::new(Provider::<Http>::try_from(rpc_url).unwrap());
+        let factory = MSAFactory::new(factory(), provider);
+        let implementation = factory.implementation().call().await.unwrap();
+        let deriver = AccountAddressDeriver::new(factory.address(), implementation);
+
+        let init_code = AccountInit::with_validator(Address::random()).init_code(Address::random());
+        for salt in [H256::zero(), H256::random()] {
+            for init_code in [Bytes::new(), init_code.clone()] {
+                let on_chain = factory.get_address(salt.into(), init_code.clone()).call().await.unwrap();
+                assert_eq!(deriver.address(salt, &init_code), on_chain);
+            }
+        }
+    }
+}
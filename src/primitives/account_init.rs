@@ -0,0 +1,138 @@
+use crate::gen::{bootstrap::InitMSACall, msa_factory::CreateAccountCall, BootstrapConfig};
+use ethers::{
+    abi::{self, AbiEncode, Token},
+    types::{Address, Bytes, H256},
+};
+
+/// Modules an ERC-7579 account is deployed with, the arguments of `Bootstrap.initMSA`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccountInit {
+    pub validators: Vec<BootstrapConfig>,
+    pub executors: Vec<BootstrapConfig>,
+    /// `module` is zero when the account has no hook.
+    pub hook: BootstrapConfig,
+    /// `data` of a fallback starts with the function selector it handles.
+    pub fallbacks: Vec<BootstrapConfig>,
+}
+
+impl AccountInit {
+    /// Account with `validator` installed without init data and no other modules.
+    pub fn with_validator(validator: Address) -> Self {
+        Self::default().validator(validator, Bytes::default())
+    }
+
+    pub fn validator(mut self, module: Address, data: Bytes) -> Self {
+        self.validators.push(BootstrapConfig { module, data });
+        self
+    }
+
+    pub fn executor(mut self, module: Address, data: Bytes) -> Self {
+        self.executors.push(BootstrapConfig { module, data });
+        self
+    }
+
+    pub fn hook(mut self, module: Address, data: Bytes) -> Self {
+        self.hook = BootstrapConfig { module, data };
+        self
+    }
+
+    pub fn fallback(mut self, module: Address, data: Bytes) -> Self {
+        self.fallbacks.push(BootstrapConfig { module, data });
+        self
+    }
+
+    /// `abi.encodeCall(Bootstrap.initMSA, (validators, executors, hook, fallbacks))`.
+    pub fn init_msa_calldata(&self) -> Bytes {
+        InitMSACall {
+            valdiators: self.validators.clone(),
+            executors: self.executors.clone(),
+            hook: self.hook.clone(),
+            fallbacks: self.fallbacks.clone(),
+        }
+        .encode()
+        .into()
+    }
+
+    /// The `initCode` passed to `MSAFactory.createAccount` and on to `initializeAccount`, which
+    /// delegatecalls `bootstrap` with the `initMSA` calldata. Same as
+    /// `Bootstrap._getInitMSACalldata`: `abi.encode(bootstrap, initMSACalldata)`.
+    pub fn init_code(&self, bootstrap: Address) -> Bytes {
+        abi::encode(&[
+            Token::Address(bootstrap),
+            Token::Bytes(self.init_msa_calldata().to_vec()),
+        ])
+        .into()
+    }
+
+    /// `factoryData` of the first user operation: `MSAFactory.createAccount(salt, initCode)`.
+    pub fn factory_data(&self, bootstrap: Address, salt: H256) -> Bytes {
+        CreateAccountCall {
+            salt: salt.into(),
+            init_code: self.init_code(bootstrap),
+        }
+        .encode()
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::{
+        primitives::{Address as a_Address, Bytes as a_Bytes, FixedBytes},
+        sol,
+        sol_types::{SolCall, SolValue},
+    };
+
+    sol! {
+        struct SolBootstrapConfig {
+            address module;
+            bytes data;
+        }
+
+        function initMSA(
+            SolBootstrapConfig[] validators,
+            SolBootstrapConfig[] executors,
+            SolBootstrapConfig hook,
+            SolBootstrapConfig[] fallbacks
+        );
+
+        function createAccount(bytes32 salt, bytes initCode);
+    }
+
+    fn sol_config(config: &BootstrapConfig) -> SolBootstrapConfig {
+        SolBootstrapConfig {
+            module: a_Address::from(config.module.0),
+            data: a_Bytes::from(config.data.to_vec()),
+        }
+    }
+
+    #[test]
+    fn encodes_factory_data_like_solidity() {
+        let bootstrap: Address = "0x5e9F3feeC2AA6706DF50de955612D964f115523B".parse().unwrap();
+        let init = AccountInit::with_validator("0x503b54Ed1E62365F0c9e4caF1479623b08acbe77".parse().unwrap())
+            .executor("0x1c7D4B196Cb0C7B01d743Fbc6116a902379C7238".parse().unwrap(), "0x1234".parse().unwrap())
+            .fallback("0xc0c374f049f2e0036B48D93346038f0133B8f00F".parse().unwrap(), "0x150b7a02".parse().unwrap());
+        let salt = H256::from_low_u64_be(7);
+
+        let init_msa = initMSACall {
+            validators: init.validators.iter().map(sol_config).collect(),
+            executors: init.executors.iter().map(sol_config).collect(),
+            hook: sol_config(&init.hook),
+            fallbacks: init.fallbacks.iter().map(sol_config).collect(),
+        }
+        .abi_encode();
+        assert_eq!(init.init_msa_calldata().to_vec(), init_msa);
+        assert_eq!(init.init_msa_calldata()[..4], [0x64, 0x22, 0x19, 0xaf]);
+
+        let init_code = (a_Address::from(bootstrap.0), a_Bytes::from(init_msa)).abi_encode_params();
+        assert_eq!(init.init_code(bootstrap).to_vec(), init_code);
+
+        let factory_data = createAccountCall {
+            salt: FixedBytes(salt.0),
+            initCode: init_code.into(),
+        }
+        .abi_encode();
+        assert_eq!(init.factory_data(bootstrap, salt).to_vec(), factory_data);
+    }
+}
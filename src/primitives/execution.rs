@@ -0,0 +1,213 @@
+use super::mode_code::{CallType, ModeCode};
+use serde::{Serialize, Deserialize};
+use ethers::{
+    abi::{self, ParamType, Tokenizable},
+    contract::{EthAbiCodec, EthAbiType},
+    types::{Address, Bytes, U256},
+};
+
+/// Selector of `execute(bytes32 mode, bytes executionCalldata)`.
+pub const EXECUTE_SELECTOR: [u8; 4] = [0xe9, 0xae, 0x5c, 0x53];
+
+/// One call made by the account, `struct Execution { address target; uint256 value; bytes callData; }`
+/// in ERC-7579.
+#[derive(
+    Default,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    EthAbiCodec,
+    EthAbiType,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Execution {
+    pub target: Address,
+    #[serde(default)]
+    pub value: U256,
+    #[serde(default)]
+    pub call_data: Bytes,
+}
+
+impl Execution {
+    pub fn new(target: Address, value: U256, call_data: Bytes) -> Self {
+        Self {
+            target,
+            value,
+            call_data,
+        }
+    }
+
+    /// Execution calldata for the single call type: `abi.encodePacked(target, value, callData)`.
+    pub fn encode_single(&self) -> Bytes {
+        let mut execution_calldata = Vec::with_capacity(52 + self.call_data.len());
+        execution_calldata.extend_from_slice(self.target.as_bytes());
+
+        let mut value_bytes = [0u8; 32];
+        self.value.to_big_endian(&mut value_bytes);
+        execution_calldata.extend_from_slice(&value_bytes);
+
+        execution_calldata.extend_from_slice(&self.call_data);
+        execution_calldata.into()
+    }
+
+    /// Reverse of [`encode_single`](Self::encode_single).
+    pub fn decode_single(execution_calldata: &[u8]) -> anyhow::Result<Self> {
+        if execution_calldata.len() < 52 {
+            return Err(anyhow::anyhow!("single execution calldata is shorter than 52 bytes"));
+        }
+        Ok(Self {
+            target: Address::from_slice(&execution_calldata[..20]),
+            value: U256::from_big_endian(&execution_calldata[20..52]),
+            call_data: execution_calldata[52..].to_vec().into(),
+        })
+    }
+
+    /// Function selector of the call, `None` for plain transfers.
+    pub fn selector(&self) -> Option<[u8; 4]> {
+        self.call_data
+            .get(..4)
+            .map(|selector| [selector[0], selector[1], selector[2], selector[3]])
+    }
+}
+
+/// Execution calldata for the batch call type: `abi.encode(Execution[])`.
+pub fn encode_batch(executions: &[Execution]) -> Bytes {
+    let executions = executions
+        .iter()
+        .cloned()
+        .map(Tokenizable::into_token)
+        .collect();
+    abi::encode(&[abi::Token::Array(executions)]).into()
+}
+
+/// Reverse of [`encode_batch`].
+pub fn decode_batch(execution_calldata: &[u8]) -> anyhow::Result<Vec<Execution>> {
+    let execution_type = ParamType::Tuple(vec![ParamType::Address, ParamType::Uint(256), ParamType::Bytes]);
+    let tokens = abi::decode(&[ParamType::Array(Box::new(execution_type))], execution_calldata)?;
+    match tokens.into_iter().next() {
+        Some(abi::Token::Array(executions)) => executions
+            .into_iter()
+            .map(|execution| Execution::from_token(execution).map_err(anyhow::Error::from))
+            .collect(),
+        _ => Err(anyhow::anyhow!("batch execution calldata is not an Execution[]")),
+    }
+}
+
+/// Decodes the calldata of an ERC-7579 `execute` call into its mode and the calls it makes. A
+/// delegate call is returned as an execution of `target` with no value.
+pub fn decode_execute(call_data: &[u8]) -> anyhow::Result<(ModeCode, Vec<Execution>)> {
+    if call_data.get(..4) != Some(&EXECUTE_SELECTOR[..]) {
+        return Err(anyhow::anyhow!("call data is not an execute call"));
+    }
+    let tokens = abi::decode(&[ParamType::FixedBytes(32), ParamType::Bytes], &call_data[4..])?;
+    let (mode, execution_calldata) = match (&tokens[0], &tokens[1]) {
+        (abi::Token::FixedBytes(mode), abi::Token::Bytes(execution_calldata)) => (mode, execution_calldata),
+        _ => return Err(anyhow::anyhow!("execute arguments are not (bytes32, bytes)")),
+    };
+    let mut encoded_mode = [0u8; 32];
+    encoded_mode.copy_from_slice(mode);
+    let mode = ModeCode::decode(encoded_mode)?;
+
+    let executions = match mode.call_type {
+        CallType::Single | CallType::Static => vec![Execution::decode_single(execution_calldata)?],
+        CallType::Batch => decode_batch(execution_calldata)?,
+        CallType::DelegateCall => {
+            if execution_calldata.len() < 20 {
+                return Err(anyhow::anyhow!("delegate call calldata is shorter than 20 bytes"));
+            }
+            vec![Execution::new(
+                Address::from_slice(&execution_calldata[..20]),
+                U256::zero(),
+                execution_calldata[20..].to_vec().into(),
+            )]
+        },
+    };
+    Ok((mode, executions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::{
+        primitives::{Address as a_Address, U256 as a_U256},
+        sol,
+        sol_types::SolValue,
+    };
+
+    sol! {
+        struct SolExecution {
+            address target;
+            uint256 value;
+            bytes callData;
+        }
+    }
+
+    fn executions() -> Vec<Execution> {
+        vec![
+            Execution::new(
+                "0x1c7D4B196Cb0C7B01d743Fbc6116a902379C7238".parse().unwrap(),
+                U256::zero(),
+                "0x095ea7b3000000000000000000000000c0c374f049f2e0036b48d93346038f0133b8f00f00000000000000000000000000000000000000000000000000000000000f4240".parse().unwrap(),
+            ),
+            Execution::new(
+                "0xc0c374f049f2e0036B48D93346038f0133B8f00F".parse().unwrap(),
+                U256::from(1_000_000_000_000_000u64),
+                Bytes::default(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn encodes_single_execution_packed() {
+        let execution = &executions()[1];
+        let encoded = execution.encode_single();
+        assert_eq!(encoded.len(), 52);
+        assert_eq!(&encoded[..20], execution.target.as_bytes());
+        assert_eq!(U256::from_big_endian(&encoded[20..52]), execution.value);
+    }
+
+    #[test]
+    fn encodes_batch_like_solidity_abi_encode() {
+        let expected = executions()
+            .into_iter()
+            .map(|execution| SolExecution {
+                target: a_Address::from(execution.target.0),
+                value: a_U256::from_limbs(execution.value.0),
+                callData: execution.call_data.to_vec().into(),
+            })
+            .collect::<Vec<_>>()
+            .abi_encode();
+        assert_eq!(encode_batch(&executions()).to_vec(), expected);
+    }
+
+    #[test]
+    fn decodes_execute_calldata() {
+        let execute = |mode: ModeCode, execution_calldata: Bytes| {
+            let args = abi::encode(&[abi::Token::FixedBytes(mode.encode().to_vec()), abi::Token::Bytes(execution_calldata.to_vec())]);
+            [&EXECUTE_SELECTOR[..], &args].concat()
+        };
+
+        let (mode, decoded) = decode_execute(&execute(ModeCode::batch(), encode_batch(&executions()))).unwrap();
+        assert_eq!(mode, ModeCode::batch());
+        assert_eq!(decoded, executions());
+        assert_eq!(decoded[0].selector(), Some([0x09, 0x5e, 0xa7, 0xb3]));
+        assert_eq!(decoded[1].selector(), None);
+
+        let (mode, decoded) = decode_execute(&execute(ModeCode::single(), executions()[0].encode_single())).unwrap();
+        assert_eq!(mode, ModeCode::single());
+        assert_eq!(decoded, vec![executions()[0].clone()]);
+
+        assert!(decode_execute(&[0x12, 0x34, 0x56, 0x78]).is_err());
+    }
+
+    #[test]
+    fn encodes_empty_batch() {
+        let encoded = encode_batch(&[]);
+        assert_eq!(encoded.len(), 64);
+        assert_eq!(U256::from_big_endian(&encoded[..32]), U256::from(32u64));
+        assert!(encoded[32..].iter().all(|byte| *byte == 0));
+    }
+}
@@ -1,2 +1,10 @@
+pub mod account_address;
+pub mod account_init;
+pub mod execution;
+pub mod mode_code;
+pub mod module;
+pub mod packed_user_operation;
+#[cfg(test)]
+pub mod test_fixtures;
 pub mod user_operation;
 pub mod utils;
\ No newline at end of file
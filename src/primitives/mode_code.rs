@@ -0,0 +1,247 @@
+use crate::errors::ModeCodeError;
+use ethers::utils::hex;
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// How the account calls the target, the first byte of the mode code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CallType {
+    #[default]
+    Single,
+    Batch,
+    Static,
+    DelegateCall,
+}
+
+impl CallType {
+    pub const fn as_u8(&self) -> u8 {
+        match self {
+            CallType::Single => 0x00,
+            CallType::Batch => 0x01,
+            CallType::Static => 0xfe,
+            CallType::DelegateCall => 0xff,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Result<Self, ModeCodeError> {
+        match value {
+            0x00 => Ok(CallType::Single),
+            0x01 => Ok(CallType::Batch),
+            0xfe => Ok(CallType::Static),
+            0xff => Ok(CallType::DelegateCall),
+            _ => Err(ModeCodeError::UnknownCallType(value)),
+        }
+    }
+}
+
+/// What happens when an execution reverts, the second byte of the mode code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExecType {
+    /// Revert the whole execution.
+    #[default]
+    Default,
+    /// Emit `TryExecuteUnsuccessful` and continue.
+    Try,
+}
+
+impl ExecType {
+    pub const fn as_u8(&self) -> u8 {
+        match self {
+            ExecType::Default => 0x00,
+            ExecType::Try => 0x01,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Result<Self, ModeCodeError> {
+        match value {
+            0x00 => Ok(ExecType::Default),
+            0x01 => Ok(ExecType::Try),
+            _ => Err(ModeCodeError::UnknownExecType(value)),
+        }
+    }
+}
+
+/// ERC-7579 execution mode, the `bytes32 mode` argument of `execute`:
+///
+/// | call type | exec type | unused  | mode selector | mode payload |
+/// |-----------|-----------|---------|---------------|--------------|
+/// | 1 byte    | 1 byte    | 4 bytes | 4 bytes       | 22 bytes     |
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ModeCode {
+    pub call_type: CallType,
+    pub exec_type: ExecType,
+    pub mode_selector: [u8; 4],
+    pub mode_payload: [u8; 22],
+}
+
+impl ModeCode {
+    pub fn new(call_type: CallType, exec_type: ExecType) -> Self {
+        Self {
+            call_type,
+            exec_type,
+            ..Default::default()
+        }
+    }
+
+    pub fn single() -> Self {
+        Self::new(CallType::Single, ExecType::Default)
+    }
+
+    pub fn batch() -> Self {
+        Self::new(CallType::Batch, ExecType::Default)
+    }
+
+    pub fn mode_selector(mut self, mode_selector: [u8; 4]) -> Self {
+        self.mode_selector = mode_selector;
+        self
+    }
+
+    pub fn mode_payload(mut self, mode_payload: [u8; 22]) -> Self {
+        self.mode_payload = mode_payload;
+        self
+    }
+
+    pub fn encode(&self) -> [u8; 32] {
+        let mut encoded = [0u8; 32];
+        encoded[0] = self.call_type.as_u8();
+        encoded[1] = self.exec_type.as_u8();
+        encoded[6..10].copy_from_slice(&self.mode_selector);
+        encoded[10..].copy_from_slice(&self.mode_payload);
+        encoded
+    }
+
+    /// Decodes a mode code, rejecting unknown call/exec types and non-zero unused bytes.
+    pub fn decode(encoded: [u8; 32]) -> Result<Self, ModeCodeError> {
+        if encoded[2..6] != [0u8; 4] {
+            return Err(ModeCodeError::NonZeroUnusedBytes);
+        }
+
+        let mut mode_selector = [0u8; 4];
+        mode_selector.copy_from_slice(&encoded[6..10]);
+        let mut mode_payload = [0u8; 22];
+        mode_payload.copy_from_slice(&encoded[10..]);
+
+        Ok(Self {
+            call_type: CallType::from_u8(encoded[0])?,
+            exec_type: ExecType::from_u8(encoded[1])?,
+            mode_selector,
+            mode_payload,
+        })
+    }
+}
+
+impl From<ModeCode> for [u8; 32] {
+    fn from(value: ModeCode) -> Self {
+        value.encode()
+    }
+}
+
+impl TryFrom<[u8; 32]> for ModeCode {
+    type Error = ModeCodeError;
+
+    fn try_from(value: [u8; 32]) -> Result<Self, Self::Error> {
+        Self::decode(value)
+    }
+}
+
+impl fmt::Display for ModeCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x")?;
+        for byte in self.encode() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ModeCode {
+    type Err = ModeCodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix("0x").unwrap_or(s);
+        if hex.len() != 64 {
+            return Err(ModeCodeError::InvalidLength);
+        }
+        let mut encoded = [0u8; 32];
+        hex::decode_to_slice(hex, &mut encoded).map_err(|_| ModeCodeError::InvalidHex)?;
+        Self::decode(encoded)
+    }
+}
+
+impl Serialize for ModeCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for ModeCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_default_modes() {
+        assert_eq!(ModeCode::single().encode(), [0u8; 32]);
+        let mut batch = [0u8; 32];
+        batch[0] = 0x01;
+        assert_eq!(ModeCode::batch().encode(), batch);
+    }
+
+    #[test]
+    fn round_trips_every_field() {
+        let mode = ModeCode::new(CallType::DelegateCall, ExecType::Try)
+            .mode_selector([0xde, 0xad, 0xbe, 0xef])
+            .mode_payload([0x11; 22]);
+        let encoded = mode.encode();
+        assert_eq!(encoded[0], 0xff);
+        assert_eq!(encoded[1], 0x01);
+        assert_eq!(encoded[2..6], [0u8; 4]);
+        assert_eq!(encoded[6..10], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(ModeCode::decode(encoded).unwrap(), mode);
+        assert_eq!(mode.to_string().parse::<ModeCode>().unwrap(), mode);
+    }
+
+    #[test]
+    fn rejects_unknown_types_and_unused_bytes() {
+        let mut encoded = [0u8; 32];
+        encoded[0] = 0x02;
+        assert!(matches!(ModeCode::decode(encoded), Err(ModeCodeError::UnknownCallType(0x02))));
+
+        let mut encoded = [0u8; 32];
+        encoded[1] = 0x02;
+        assert!(matches!(ModeCode::decode(encoded), Err(ModeCodeError::UnknownExecType(0x02))));
+
+        let mut encoded = [0u8; 32];
+        encoded[3] = 0x01;
+        assert!(matches!(ModeCode::decode(encoded), Err(ModeCodeError::NonZeroUnusedBytes)));
+    }
+
+    #[test]
+    fn serializes_as_hex_string() {
+        let value = serde_json::to_value(ModeCode::new(CallType::Batch, ExecType::Try)).unwrap();
+        assert_eq!(value, serde_json::json!("0x0101000000000000000000000000000000000000000000000000000000000000"));
+    }
+
+    #[test]
+    fn rejects_non_hex_strings_of_the_right_length() {
+        assert_eq!(ModeCode::from_str(&"zz".repeat(32)), Err(ModeCodeError::InvalidHex));
+        // 64 bytes, but 32 characters of two bytes each.
+        assert_eq!(ModeCode::from_str(&"é".repeat(32)), Err(ModeCodeError::InvalidHex));
+        assert_eq!(ModeCode::from_str(&format!("0x{}", "0".repeat(62))), Err(ModeCodeError::InvalidLength));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::errors::ModuleTypeError;
+use crate::primitives::mode_code::CallType;
+use ethers::{
+    abi::{self, Token},
+    types::{Address, Bytes, H160, U256},
+};
+use serde::{Serialize, Deserialize};
+use std::fmt;
+
+/// Head and tail of the validator and executor lists of the account (`SentinelList`).
+pub const SENTINEL: Address = H160([
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+]);
+
+/// ERC-7579 module type ids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum ModuleType {
+    Validator,
+    Executor,
+    Fallback,
+    Hook,
+}
+
+impl ModuleType {
+    pub const fn id(&self) -> u8 {
+        match self {
+            ModuleType::Validator => 1,
+            ModuleType::Executor => 2,
+            ModuleType::Fallback => 3,
+            ModuleType::Hook => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, ModuleTypeError> {
+        match id {
+            1 => Ok(ModuleType::Validator),
+            2 => Ok(ModuleType::Executor),
+            3 => Ok(ModuleType::Fallback),
+            4 => Ok(ModuleType::Hook),
+            _ => Err(ModuleTypeError(id)),
+        }
+    }
+}
+
+impl From<ModuleType> for u8 {
+    fn from(value: ModuleType) -> Self {
+        value.id()
+    }
+}
+
+impl From<ModuleType> for U256 {
+    fn from(value: ModuleType) -> Self {
+        U256::from(value.id())
+    }
+}
+
+impl TryFrom<u8> for ModuleType {
+    type Error = ModuleTypeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Self::from_id(value)
+    }
+}
+
+impl fmt::Display for ModuleType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ModuleType::Validator => "validator",
+            ModuleType::Executor => "executor",
+            ModuleType::Fallback => "fallback",
+            ModuleType::Hook => "hook",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Fallback handler the account routes calls with `selector` to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActiveFallbackHandler {
+    pub selector: Bytes,
+    pub handler: Address,
+    pub call_type: CallType,
+}
+
+/// Module configuration of an account as reported by the account itself.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountModules {
+    pub account: Address,
+    pub deployed: bool,
+    pub validators: Vec<Address>,
+    pub executors: Vec<Address>,
+    /// `None` when no hook is installed or the account does not support hooks.
+    pub hook: Option<Address>,
+    /// Handlers of the inspected selectors that have one; fallbacks cannot be enumerated.
+    pub fallbacks: Vec<ActiveFallbackHandler>,
+}
+
+/// Selectors inspected when none are given: the ERC-721 and ERC-1155 token receiver callbacks.
+pub const DEFAULT_FALLBACK_SELECTORS: [[u8; 4]; 3] = [
+    [0x15, 0x0b, 0x7a, 0x02], // onERC721Received
+    [0xf2, 0x3a, 0x6e, 0x61], // onERC1155Received
+    [0xbc, 0x19, 0x7c, 0x81], // onERC1155BatchReceived
+];
+
+/// `initData` of a fallback handler: `abi.encodePacked(selector, callType, onInstallData)`.
+pub fn fallback_install_data(selector: [u8; 4], call_type: CallType, on_install_data: &[u8]) -> Bytes {
+    let mut data = Vec::with_capacity(5 + on_install_data.len());
+    data.extend_from_slice(&selector);
+    data.push(call_type.as_u8());
+    data.extend_from_slice(on_install_data);
+    data.into()
+}
+
+/// `deInitData` of a fallback handler: `abi.encodePacked(selector, onUninstallData)`.
+pub fn fallback_uninstall_data(selector: [u8; 4], on_uninstall_data: &[u8]) -> Bytes {
+    let mut data = Vec::with_capacity(4 + on_uninstall_data.len());
+    data.extend_from_slice(&selector);
+    data.extend_from_slice(on_uninstall_data);
+    data.into()
+}
+
+/// `deInitData` of a validator or executor, which are unlinked from a list:
+/// `abi.encode(prev, onUninstallData)`.
+pub fn linked_uninstall_data(prev: Address, on_uninstall_data: &[u8]) -> Bytes {
+    abi::encode(&[Token::Address(prev), Token::Bytes(on_uninstall_data.to_vec())]).into()
+}
+
+/// `additionalContext` of `isModuleInstalled` for a fallback handler, `abi.encode(selector)`.
+pub fn fallback_context(selector: [u8; 4]) -> Bytes {
+    abi::encode(&[Token::FixedBytes(selector.to_vec())]).into()
+}
+
+/// Parses a `0x`-prefixed 4-byte function selector.
+pub fn parse_selector(selector: &str) -> anyhow::Result<[u8; 4]> {
+    let bytes: Bytes = selector.trim().parse()?;
+    <[u8; 4]>::try_from(bytes.as_ref())
+        .map_err(|_| anyhow::anyhow!("selector {:?} is not 4 bytes", selector))
+}
+
+/// Selector a fallback handler's install or uninstall data starts with.
+pub fn fallback_selector(data: &[u8]) -> Option<[u8; 4]> {
+    data.get(..4).map(|selector| [selector[0], selector[1], selector[2], selector[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_module_type_as_id() {
+        assert_eq!(serde_json::to_value(ModuleType::Fallback).unwrap(), serde_json::json!(3));
+        assert_eq!(serde_json::from_value::<ModuleType>(serde_json::json!(4)).unwrap(), ModuleType::Hook);
+        assert!(serde_json::from_value::<ModuleType>(serde_json::json!(5)).is_err());
+    }
+
+    #[test]
+    fn encodes_fallback_and_linked_data() {
+        let selector = [0x15, 0x0b, 0x7a, 0x02];
+        let install = fallback_install_data(selector, CallType::Static, &[0xaa]);
+        assert_eq!(install.to_vec(), vec![0x15, 0x0b, 0x7a, 0x02, 0xfe, 0xaa]);
+        assert_eq!(fallback_selector(&install), Some(selector));
+        assert_eq!(parse_selector("0x150b7a02").unwrap(), selector);
+        assert!(parse_selector("0x150b7a").is_err());
+        assert_eq!(fallback_uninstall_data(selector, &[]).to_vec(), selector.to_vec());
+
+        let context = fallback_context(selector);
+        assert_eq!(context.len(), 32);
+        assert_eq!(context[..4], selector);
+
+        let deinit = linked_uninstall_data(SENTINEL, &[0xbb]);
+        let tokens = abi::decode(&[abi::ParamType::Address, abi::ParamType::Bytes], &deinit).unwrap();
+        assert_eq!(tokens, vec![Token::Address(SENTINEL), Token::Bytes(vec![0xbb])]);
+        assert_eq!(SENTINEL, Address::from_low_u64_be(1));
+    }
+}
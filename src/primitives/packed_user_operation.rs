@@ -0,0 +1,447 @@
+use super::user_operation::{UserOperation, UserOperationHash};
+use crate::errors::Uint128Overflow;
+use serde::{Serialize, Deserialize};
+use std::ops::Deref;
+use ethers::{
+    abi::AbiEncode,
+    contract::{EthAbiCodec, EthAbiType},
+    types::{Address, Bytes, H256, U256},
+    utils::keccak256,
+};
+
+/// `PackedUserOperation` as defined by the ERC-4337 v0.7 EntryPoint.
+///
+/// Gas limits and fees are packed as two `uint128` in one `bytes32` (high half first), `initCode`
+/// is `factory ++ factoryData` and `paymasterAndData` is
+/// `paymaster ++ uint128(verificationGasLimit) ++ uint128(postOpGasLimit) ++ paymasterData`.
+#[derive(
+    Default,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    EthAbiCodec,
+    EthAbiType,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PackedUserOperation {
+    pub sender: Address,
+    pub nonce: U256,
+    pub init_code: Bytes,
+    pub call_data: Bytes,
+    pub account_gas_limits: H256,
+    pub pre_verification_gas: U256,
+    pub gas_fees: H256,
+    pub paymaster_and_data: Bytes,
+    pub signature: Bytes,
+}
+
+/// The fields of a `PackedUserOperation` as they enter `UserOperationLib.encode`, with the
+/// dynamic fields replaced by their hashes and without the signature.
+#[derive(Clone, Debug, PartialEq, Eq, EthAbiCodec, EthAbiType)]
+pub struct PackedUserOperationUnsigned {
+    pub sender: Address,
+    pub nonce: U256,
+    pub hash_init_code: H256,
+    pub hash_call_data: H256,
+    pub account_gas_limits: H256,
+    pub pre_verification_gas: U256,
+    pub gas_fees: H256,
+    pub hash_paymaster_and_data: H256,
+}
+
+/// Packs `high` and `low` into one word as `high << 128 | low`.
+pub fn pack_uints(high: u128, low: u128) -> H256 {
+    let mut packed = [0u8; 32];
+    packed[..16].copy_from_slice(&high.to_be_bytes());
+    packed[16..].copy_from_slice(&low.to_be_bytes());
+    H256::from(packed)
+}
+
+/// Reverse of [`pack_uints`], returning `(high, low)`.
+pub fn unpack_uints(packed: H256) -> (U256, U256) {
+    (
+        U256::from_big_endian(&packed.as_bytes()[..16]),
+        U256::from_big_endian(&packed.as_bytes()[16..]),
+    )
+}
+
+/// `value` of the operation field `field` as a `uint128`. The EntryPoint would read a truncated
+/// value, so a larger one is an error rather than a cast.
+pub fn uint128(field: &'static str, value: U256) -> Result<u128, Uint128Overflow> {
+    if value > U256::from(u128::MAX) {
+        return Err(Uint128Overflow(field, value));
+    }
+    Ok(value.as_u128())
+}
+
+impl PackedUserOperation {
+
+    pub fn pack(&self) -> Bytes {
+        self.clone().encode().into()
+    }
+
+    pub fn pack_without_signature(&self) -> Bytes {
+        PackedUserOperationUnsigned::from(self).encode().into()
+    }
+
+    /// Same value as `EntryPoint.getUserOpHash`:
+    /// `keccak256(abi.encode(keccak256(encode(userOp)), entryPoint, chainId))`.
+    pub fn hash(&self, entry_point: &Address, chain_id: &U256) -> UserOperationHash {
+        H256::from_slice(
+            keccak256(
+                [
+                    keccak256(self.pack_without_signature().deref()).to_vec(),
+                    entry_point.encode(),
+                    chain_id.encode(),
+                ]
+                .concat(),
+            )
+            .as_slice(),
+        )
+        .into()
+    }
+
+    pub fn call_gas_limit(&self) -> U256 {
+        unpack_uints(self.account_gas_limits).1
+    }
+
+    pub fn verification_gas_limit(&self) -> U256 {
+        unpack_uints(self.account_gas_limits).0
+    }
+
+    pub fn max_fee_per_gas(&self) -> U256 {
+        unpack_uints(self.gas_fees).1
+    }
+
+    pub fn max_priority_fee_per_gas(&self) -> U256 {
+        unpack_uints(self.gas_fees).0
+    }
+}
+
+impl From<&PackedUserOperation> for PackedUserOperationUnsigned {
+    fn from(value: &PackedUserOperation) -> Self {
+        Self {
+            sender: value.sender,
+            nonce: value.nonce,
+            hash_init_code: keccak256(value.init_code.deref()).into(),
+            hash_call_data: keccak256(value.call_data.deref()).into(),
+            account_gas_limits: value.account_gas_limits,
+            pre_verification_gas: value.pre_verification_gas,
+            gas_fees: value.gas_fees,
+            hash_paymaster_and_data: keccak256(value.paymaster_and_data.deref()).into(),
+        }
+    }
+}
+
+impl TryFrom<UserOperation> for PackedUserOperation {
+    type Error = Uint128Overflow;
+
+    /// Fails when a gas limit or fee does not fit in its `uint128` half.
+    fn try_from(value: UserOperation) -> Result<Self, Self::Error> {
+        let init_code = if value.factory.is_zero() {
+            Bytes::default()
+        } else {
+            [value.factory.as_bytes(), value.factory_data.deref()].concat().into()
+        };
+
+        let paymaster_and_data = match value.paymaster {
+            Some(paymaster) => {
+                let mut paymaster_and_data = paymaster.as_bytes().to_vec();
+                paymaster_and_data.extend_from_slice(
+                    pack_uints(
+                        uint128("paymasterVerificationGasLimit", value.paymaster_verification_gas_limit)?,
+                        uint128("paymasterPostOpGasLimit", value.paymaster_post_op_gas_limit)?,
+                    )
+                    .as_bytes(),
+                );
+                paymaster_and_data.extend_from_slice(&value.paymaster_data);
+                paymaster_and_data.into()
+            },
+            None => Bytes::default(),
+        };
+
+        Ok(Self {
+            sender: value.sender,
+            nonce: value.nonce,
+            init_code,
+            call_data: value.call_data,
+            account_gas_limits: pack_uints(
+                uint128("verificationGasLimit", value.verification_gas_limit)?,
+                uint128("callGasLimit", value.call_gas_limit)?,
+            ),
+            pre_verification_gas: value.pre_verification_gas,
+            gas_fees: pack_uints(
+                uint128("maxPriorityFeePerGas", value.max_priority_fee_per_gas)?,
+                uint128("maxFeePerGas", value.max_fee_per_gas)?,
+            ),
+            paymaster_and_data,
+            signature: value.signature,
+        })
+    }
+}
+
+impl From<PackedUserOperation> for UserOperation {
+    fn from(value: PackedUserOperation) -> Self {
+        let (factory, factory_data) = if value.init_code.len() >= 20 {
+            (
+                Address::from_slice(&value.init_code[..20]),
+                Bytes::from(value.init_code[20..].to_vec()),
+            )
+        } else {
+            (Address::zero(), Bytes::default())
+        };
+
+        let (paymaster, paymaster_verification_gas_limit, paymaster_post_op_gas_limit, paymaster_data) =
+            if value.paymaster_and_data.len() >= 52 {
+                let paymaster_and_data = value.paymaster_and_data.deref();
+                (
+                    Some(Address::from_slice(&paymaster_and_data[..20])),
+                    U256::from_big_endian(&paymaster_and_data[20..36]),
+                    U256::from_big_endian(&paymaster_and_data[36..52]),
+                    Bytes::from(paymaster_and_data[52..].to_vec()),
+                )
+            } else {
+                (None, U256::zero(), U256::zero(), Bytes::default())
+            };
+
+        let (verification_gas_limit, call_gas_limit) = unpack_uints(value.account_gas_limits);
+        let (max_priority_fee_per_gas, max_fee_per_gas) = unpack_uints(value.gas_fees);
+
+        Self {
+            sender: value.sender,
+            nonce: value.nonce,
+            factory,
+            factory_data,
+            call_data: value.call_data,
+            call_gas_limit,
+            verification_gas_limit,
+            pre_verification_gas: value.pre_verification_gas,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            paymaster,
+            paymaster_verification_gas_limit,
+            paymaster_post_op_gas_limit,
+            paymaster_data,
+            signature: value.signature,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::test_fixtures::{entry_point, user_operation, SEPOLIA_CHAIN_ID};
+    use alloy::{
+        primitives::{keccak256 as a_keccak256, Address as a_Address, FixedBytes, U256 as a_U256},
+        sol_types::SolValue,
+    };
+
+    fn sepolia() -> U256 {
+        U256::from(SEPOLIA_CHAIN_ID)
+    }
+
+    fn pack(uo: UserOperation) -> PackedUserOperation {
+        PackedUserOperation::try_from(uo).unwrap()
+    }
+
+    fn deployed_account_uo() -> UserOperation {
+        user_operation()
+            .nonce(U256::from_dec_str("1497194025692410451434796957446389290216128096620494602240").unwrap())
+            .call_data("0xe9ae5c53000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000400000000000000000000000000000000000000000000000000000000000000034c0c374f049f2e0036b48d93346038f0133b8f00f00000000000000000000000000000000000000000000000000038d7ea4c68000000000000000000000000000".parse().unwrap())
+            .signature("0x1234".parse().unwrap())
+    }
+
+    fn sponsored_deployment_uo() -> UserOperation {
+        deployed_account_uo()
+            .nonce(U256::zero())
+            .factory("0xc1f3f2dBbe9498FE9A2Fd75dEa6507A57033fe42".parse().unwrap())
+            .factory_data("0xf8a59370aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".parse().unwrap())
+            .paymaster(Some("0x0000000000000039cd5e8aE05257CE51C473ddd1".parse().unwrap()))
+            .paymaster_verification_gas_limit(U256::from(40_000u64))
+            .paymaster_post_op_gas_limit(U256::from(1u64))
+            .paymaster_data("0xdeadbeef".parse().unwrap())
+    }
+
+    fn to_alloy_address(address: Address) -> a_Address {
+        a_Address::from(address.0)
+    }
+
+    fn to_alloy_u256(value: U256) -> a_U256 {
+        a_U256::from_limbs(value.0)
+    }
+
+    fn to_alloy_b256(value: H256) -> FixedBytes<32> {
+        FixedBytes::from(value.0)
+    }
+
+    /// Independent implementation of `EntryPoint.getUserOpHash` on top of alloy's ABI encoder.
+    fn reference_hash(uo: &PackedUserOperation, entry_point: Address, chain_id: U256) -> H256 {
+        let inner = (
+            to_alloy_address(uo.sender),
+            to_alloy_u256(uo.nonce),
+            a_keccak256(uo.init_code.deref()),
+            a_keccak256(uo.call_data.deref()),
+            to_alloy_b256(uo.account_gas_limits),
+            to_alloy_u256(uo.pre_verification_gas),
+            to_alloy_b256(uo.gas_fees),
+            a_keccak256(uo.paymaster_and_data.deref()),
+        )
+            .abi_encode_params();
+        let outer = (a_keccak256(inner), to_alloy_address(entry_point), to_alloy_u256(chain_id)).abi_encode_params();
+        H256::from(a_keccak256(outer).0)
+    }
+
+    #[test]
+    fn packs_gas_limits_and_fees_high_half_first() {
+        let packed = pack(deployed_account_uo());
+        assert_eq!(
+            packed.account_gas_limits,
+            "0x00000000000000000000000000012b2700000000000000000000000000004623".parse::<H256>().unwrap()
+        );
+        assert_eq!(
+            packed.gas_fees,
+            "0x0000000000000000000000003b9aca00000000000000000000000000b2d05e00".parse::<H256>().unwrap()
+        );
+        assert_eq!(packed.call_gas_limit(), U256::from(17955u64));
+        assert_eq!(packed.verification_gas_limit(), U256::from(76583u64));
+        assert_eq!(packed.max_fee_per_gas(), U256::from(3_000_000_000u64));
+        assert_eq!(packed.max_priority_fee_per_gas(), U256::from(1_000_000_000u64));
+    }
+
+    #[test]
+    fn omits_init_code_and_paymaster_and_data_when_absent() {
+        let packed = pack(deployed_account_uo());
+        assert!(packed.init_code.is_empty());
+        assert!(packed.paymaster_and_data.is_empty());
+    }
+
+    #[test]
+    fn packs_init_code_and_paymaster_and_data() {
+        let packed = pack(sponsored_deployment_uo());
+        assert_eq!(
+            packed.init_code,
+            "0xc1f3f2dbbe9498fe9a2fd75dea6507a57033fe42f8a59370aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".parse::<Bytes>().unwrap()
+        );
+        assert_eq!(
+            packed.paymaster_and_data,
+            "0x0000000000000039cd5e8ae05257ce51c473ddd100000000000000000000000000009c4000000000000000000000000000000001deadbeef".parse::<Bytes>().unwrap()
+        );
+    }
+
+    #[test]
+    fn round_trips_through_user_operation() {
+        let uo = sponsored_deployment_uo();
+        let unpacked = UserOperation::from(pack(uo.clone()));
+        assert_eq!(unpacked.factory, uo.factory);
+        assert_eq!(unpacked.factory_data, uo.factory_data);
+        assert_eq!(unpacked.paymaster, uo.paymaster);
+        assert_eq!(unpacked.paymaster_data, uo.paymaster_data);
+        assert_eq!(pack(unpacked), pack(uo));
+    }
+
+    /// Known `EntryPoint.getUserOpHash` answers at `ENTRY_POINT_SEPOLIA_V7` on Sepolia for the
+    /// empty, deployed account and sponsored deployment operations, computed outside this crate.
+    /// `hash_matches_known_answers` pins them and `matches_on_chain_get_user_op_hash` checks them
+    /// against the deployed EntryPoint.
+    fn known_answers() -> [(UserOperation, H256); 3] {
+        [
+            (
+                UserOperation::default(),
+                "0xc2ae9ba70cf313be10ea729190547ac0dfa49cb6501877a84bec112f30781863".parse().unwrap(),
+            ),
+            (
+                deployed_account_uo(),
+                "0x9dd5e0fea6abe6954f21d37cb7d503ab7411823d1944f87719f4b104de36a19c".parse().unwrap(),
+            ),
+            (
+                sponsored_deployment_uo(),
+                "0x50c19790b9096a5ff1a10e433c9e443e90317d4ef3966a6c24b3280c0edafdf9".parse().unwrap(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn hash_matches_known_answers() {
+        for (uo, expected) in known_answers() {
+            assert_eq!(pack(uo.clone()).hash(&entry_point(), &sepolia()).0, expected);
+            assert_eq!(uo.hash(&entry_point(), &sepolia()).unwrap().0, expected);
+        }
+    }
+
+    #[test]
+    fn hash_matches_entry_point_encoding() {
+        for (uo, _) in known_answers() {
+            let packed = pack(uo);
+            assert_eq!(packed.hash(&entry_point(), &sepolia()).0, reference_hash(&packed, entry_point(), sepolia()));
+        }
+    }
+
+    #[test]
+    fn packing_rejects_gas_limits_and_fees_above_uint128() {
+        let too_large = U256::from(u128::MAX) + 1;
+        assert_eq!(
+            PackedUserOperation::try_from(deployed_account_uo().call_gas_limit(too_large)),
+            Err(Uint128Overflow("callGasLimit", too_large))
+        );
+        assert_eq!(
+            PackedUserOperation::try_from(deployed_account_uo().max_fee_per_gas(too_large)),
+            Err(Uint128Overflow("maxFeePerGas", too_large))
+        );
+        assert_eq!(
+            sponsored_deployment_uo()
+                .paymaster_post_op_gas_limit(too_large)
+                .hash(&entry_point(), &sepolia()),
+            Err(Uint128Overflow("paymasterPostOpGasLimit", too_large))
+        );
+
+        let largest = pack(deployed_account_uo().call_gas_limit(U256::from(u128::MAX)));
+        assert_eq!(largest.call_gas_limit(), U256::from(u128::MAX));
+    }
+
+    /// Checks the known answers against `getUserOpHash` of the deployed EntryPoint; needs
+    /// `SEPOLIA_RPC_ENDPOINT`.
+    #[tokio::test]
+    #[ignore]
+    async fn matches_on_chain_get_user_op_hash() {
+        use crate::userop_middleware::EntryPoint;
+        use ethers::providers::{Http, Provider};
+        use std::sync::Arc;
+
+        dotenv::dotenv().ok();
+        let rpc_url = std::env::var("SEPOLIA_RPC_ENDPOINT").unwrap();
+        let provider = Arc::new(Provider::<Http>::try_from(rpc_url).unwrap());
+        let entry_point = EntryPoint::new(entry_point(), provider);
+        for (uo, expected) in known_answers() {
+            let on_chain: [u8; 32] = entry_point
+                .method("getUserOpHash", (pack(uo),))
+                .unwrap()
+                .call()
+                .await
+                .unwrap();
+            assert_eq!(H256::from(on_chain), expected);
+        }
+    }
+
+    #[test]
+    fn hash_ignores_signature_and_commits_to_chain_and_entry_point() {
+        let uo = deployed_account_uo();
+        let hash = uo.hash(&entry_point(), &sepolia()).unwrap();
+        assert_eq!(uo.clone().signature(Bytes::default()).hash(&entry_point(), &sepolia()).unwrap(), hash);
+        assert_ne!(uo.hash(&entry_point(), &U256::one()).unwrap(), hash);
+        assert_ne!(uo.hash(&Address::zero(), &sepolia()).unwrap(), hash);
+    }
+
+    #[test]
+    fn abi_layout_matches_entry_point_abi() {
+        use ethers::abi::AbiType;
+        let entry_point_abi: ethers::abi::Abi = serde_json::from_value(
+            serde_json::from_str::<serde_json::Value>(include_str!("../abi/EntryPoint.json")).unwrap()["abi"].clone(),
+        )
+        .unwrap();
+        let get_user_op_hash = entry_point_abi.function("getUserOpHash").unwrap();
+        assert_eq!(get_user_op_hash.inputs[0].kind, PackedUserOperation::param_type());
+    }
+}
@@ -0,0 +1,32 @@
+//! Values shared by the unit tests.
+
+use crate::consts::ENTRY_POINT_SEPOLIA_V7;
+use crate::primitives::user_operation::UserOperation;
+use ethers::{
+    signers::LocalWallet,
+    types::{Address, U256},
+};
+
+pub const SEPOLIA_CHAIN_ID: u64 = 11155111;
+
+/// Well-known test key, never to hold funds.
+pub fn wallet() -> LocalWallet {
+    "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318".parse().unwrap()
+}
+
+pub fn entry_point() -> Address {
+    ENTRY_POINT_SEPOLIA_V7.parse().unwrap()
+}
+
+/// Unsigned user operation of a deployed account, with every gas field set and no paymaster.
+pub fn user_operation() -> UserOperation {
+    UserOperation::default()
+        .sender("0x8F3B5C7B0BcBb6A3bA3E12ABB1e1F5BbA5b9a6E3".parse().unwrap())
+        .nonce(U256::from(3u64))
+        .call_data("0xe9ae5c53".parse().unwrap())
+        .call_gas_limit(U256::from(17955u64))
+        .verification_gas_limit(U256::from(76583u64))
+        .pre_verification_gas(U256::from(47892u64))
+        .max_fee_per_gas(U256::from(3_000_000_000u64))
+        .max_priority_fee_per_gas(U256::from(1_000_000_000u64))
+}
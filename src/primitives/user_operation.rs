@@ -1,16 +1,16 @@
 use super::utils::as_checksum;
+use super::packed_user_operation::PackedUserOperation;
+use crate::errors::Uint128Overflow;
 use serde::{Serialize, Deserialize};
 use rustc_hex::FromHexError;
-use ssz_rs::Sized;
-use std::{
-    ops::{AddAssign, Deref},
-    slice::Windows,
-    str::FromStr,
-};
-use ethers::{
-    abi::AbiEncode, contract::{EthAbiCodec, EthAbiType}, core::k256::elliptic_curve::consts::U245, middleware::transformer::ds_proxy::factory, types::{Address, Bytes, Log, TransactionReceipt, H256, U256, U64}, utils::keccak256
-};
-
+use std::str::FromStr;
+use ethers::types::{Address, Bytes, Log, TransactionReceipt, H256, U256, U64};
+
+/// ERC-4337 v0.7 user operation in its unpacked (RPC) form.
+///
+/// `factory` is the zero address for an already deployed account and `paymaster` is `None`
+/// for a self-funded operation. Serialization goes through [`UserOperationPartial`], so the
+/// factory and paymaster fields are omitted from JSON when they are absent.
 #[derive(
     Default,
     Clone,
@@ -19,12 +19,9 @@ use ethers::{
     PartialOrd,
     PartialEq,
     Eq,
-    Serialize, 
     Deserialize,
-    EthAbiCodec,
-    EthAbiType,
 )]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct UserOperation {
     pub sender: Address,
     pub nonce: U256,
@@ -36,7 +33,7 @@ pub struct UserOperation {
     pub pre_verification_gas: U256,
     pub max_fee_per_gas: U256,
     pub max_priority_fee_per_gas: U256,
-    pub paymaster: String,
+    pub paymaster: Option<Address>,
     pub paymaster_verification_gas_limit: U256,
     pub paymaster_post_op_gas_limit: U256,
     pub paymaster_data: Bytes,
@@ -45,28 +42,18 @@ pub struct UserOperation {
 
 impl UserOperation {
 
-    pub fn pack(&self) -> Bytes {
-        self.clone().encode().into()
+    pub fn pack(&self) -> Result<Bytes, Uint128Overflow> {
+        Ok(PackedUserOperation::try_from(self.clone())?.pack())
     }
 
-    pub fn pack_without_signature(&self) -> Bytes {
-        let user_operation_packed = UserOperationUnsigned::from(self.clone());
-        user_operation_packed.encode().into()
+    pub fn pack_without_signature(&self) -> Result<Bytes, Uint128Overflow> {
+        Ok(PackedUserOperation::try_from(self.clone())?.pack_without_signature())
     }
 
-    pub fn hash(&self, entry_point: &Address, chain_id: &U256) -> UserOperationHash {
-        H256::from_slice(
-            keccak256(
-                [
-                    keccak256(self.pack_without_signature().deref()).to_vec(),
-                    entry_point.encode(),
-                    chain_id.encode(),
-                ]
-                .concat(),
-            )
-            .as_slice(),
-        )
-        .into()
+    /// ERC-4337 v0.7 user operation hash, equal to `EntryPoint.getUserOpHash`. Fails when a gas
+    /// limit or fee does not fit in `uint128`.
+    pub fn hash(&self, entry_point: &Address, chain_id: &U256) -> Result<UserOperationHash, Uint128Overflow> {
+        Ok(PackedUserOperation::try_from(self.clone())?.hash(entry_point, chain_id))
     }
 
     pub fn sender(mut self, sender: Address) -> Self {
@@ -119,7 +106,7 @@ impl UserOperation {
         self
     }
 
-    pub fn paymaster(mut self, paymaster: String) -> Self {
+    pub fn paymaster(mut self, paymaster: Option<Address>) -> Self {
         self.paymaster = paymaster;
         self
     }
@@ -146,6 +133,15 @@ impl UserOperation {
 
 }
 
+impl Serialize for UserOperation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        UserOperationPartial::from(self.clone()).serialize(serializer)
+    }
+}
+
 // Here starts for UserOperationHash
 #[derive(
     Eq, Hash, PartialEq, Debug, Serialize, Deserialize, Clone, Copy, Default, PartialOrd, Ord
@@ -209,45 +205,6 @@ impl UserOperationHash {
     }
 }
 
-#[derive(EthAbiCodec, EthAbiType)]
-pub struct UserOperationUnsigned {
-    pub sender: Address,
-    pub nonce: U256,
-    pub factory: Address,
-    pub factory_data: Bytes,
-    pub call_data: Bytes,
-    pub call_gas_limit: U256,
-    pub verification_gas_limit: U256,
-    pub pre_verification_gas: U256,
-    pub max_fee_per_gas: U256,
-    pub max_priority_fee_per_gas: U256,
-    pub paymaster: String,
-    pub paymaster_verification_gas_limit: U256,
-    pub paymaster_post_op_gas_limit: U256,
-    pub paymaster_data: Bytes,
-}
-
-impl From<UserOperation> for UserOperationUnsigned {
-    fn from(value: UserOperation) -> Self {
-        Self {
-            sender: value.sender,
-            nonce: value.nonce,
-            factory: value.factory,
-            factory_data: value.factory_data,
-            call_data: keccak256(value.call_data.deref()).into(),
-            call_gas_limit: value.call_gas_limit,
-            verification_gas_limit: value.verification_gas_limit,
-            pre_verification_gas: value.pre_verification_gas,
-            max_fee_per_gas: value.max_fee_per_gas,
-            max_priority_fee_per_gas: value.max_priority_fee_per_gas,
-            paymaster: value.paymaster,
-            paymaster_verification_gas_limit: value.paymaster_verification_gas_limit,
-            paymaster_post_op_gas_limit: value.paymaster_post_op_gas_limit,
-            paymaster_data: value.paymaster_data,
-        }
-    }
-}
-
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserOperationReceipt {
@@ -267,35 +224,78 @@ pub struct UserOperationReceipt {
     pub tx_receipt: TransactionReceipt
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserOperationByHash {
-    pub user_operation: UserOperation,
+    /// RPC form as returned by the bundler, which may send absent fields as `null`.
+    pub user_operation: UserOperationPartial,
     #[serde(serialize_with = "as_checksum")]
     pub entry_point: Address,
     pub transaction_hash: H256,
     pub block_hash: H256,
-    pub block_number: u64,
+    pub block_number: U64,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+/// User operation with every field optional, used while it is being built and as the JSON-RPC
+/// representation. Fields that are `None` are left out of the JSON object, which is how bundlers
+/// expect an absent factory or paymaster to be sent.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserOperationPartial {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sender: Option<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub nonce: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub factory: Option<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub factory_data: Option<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub call_data: Option<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub call_gas_limit: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub verification_gas_limit: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub pre_verification_gas: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_fee_per_gas: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub max_priority_fee_per_gas: Option<U256>,
-    pub paymaster: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paymaster: Option<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub paymaster_verification_gas_limit: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub paymaster_post_op_gas_limit: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub paymaster_data: Option<Bytes>,
-    pub signature: Option<Bytes>
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Bytes>,
+}
+
+impl From<UserOperation> for UserOperationPartial {
+    fn from(user_operation: UserOperation) -> Self {
+        let has_factory = !user_operation.factory.is_zero();
+        let has_paymaster = user_operation.paymaster.is_some();
+        Self {
+            sender: Some(user_operation.sender),
+            nonce: Some(user_operation.nonce),
+            factory: has_factory.then_some(user_operation.factory),
+            factory_data: has_factory.then_some(user_operation.factory_data),
+            call_data: Some(user_operation.call_data),
+            call_gas_limit: Some(user_operation.call_gas_limit),
+            verification_gas_limit: Some(user_operation.verification_gas_limit),
+            pre_verification_gas: Some(user_operation.pre_verification_gas),
+            max_fee_per_gas: Some(user_operation.max_fee_per_gas),
+            max_priority_fee_per_gas: Some(user_operation.max_priority_fee_per_gas),
+            paymaster: user_operation.paymaster,
+            paymaster_verification_gas_limit: has_paymaster.then_some(user_operation.paymaster_verification_gas_limit),
+            paymaster_post_op_gas_limit: has_paymaster.then_some(user_operation.paymaster_post_op_gas_limit),
+            paymaster_data: has_paymaster.then_some(user_operation.paymaster_data),
+            signature: Some(user_operation.signature),
+        }
+    }
 }
 
 impl From<UserOperationPartial> for UserOperation {
@@ -322,20 +322,8 @@ impl From<UserOperationPartial> for UserOperation {
                     Address::zero()
                 }
             },
-            factory_data: {
-                if let Some(factory_data) = user_operation.factory_data {
-                    factory_data
-                } else {
-                    Bytes::default()
-                }
-            },
-            call_data: {
-                if let Some(call_data) = user_operation.call_data {
-                    call_data
-                } else {
-                    Bytes::default()
-                }
-            },
+            factory_data: user_operation.factory_data.unwrap_or_default(),
+            call_data: user_operation.call_data.unwrap_or_default(),
             call_gas_limit: {
                 if let Some(call_gas_limit) = user_operation.call_gas_limit {
                     call_gas_limit
@@ -371,13 +359,7 @@ impl From<UserOperationPartial> for UserOperation {
                     U256::zero()
                 }
             },
-            paymaster: {
-                if let Some(paymaster) = user_operation.paymaster {
-                    paymaster
-                } else {
-                    "0x".to_string()
-                }
-            },
+            paymaster: user_operation.paymaster,
             paymaster_verification_gas_limit: {
                 if let Some(paymaster_verification_gas_limit) = user_operation.paymaster_verification_gas_limit {
                     paymaster_verification_gas_limit
@@ -392,20 +374,8 @@ impl From<UserOperationPartial> for UserOperation {
                     U256::zero()
                 }
             },
-            paymaster_data: {
-                if let Some(paymaster_data) = user_operation.paymaster_data {
-                    paymaster_data
-                } else {
-                    Bytes::default()
-                }
-            },
-            signature: {
-                if let Some(signature) = user_operation.signature {
-                    signature
-                } else {
-                    Bytes::default()
-                }
-            },
+            paymaster_data: user_operation.paymaster_data.unwrap_or_default(),
+            signature: user_operation.signature.unwrap_or_default(),
         }
     }
 }
@@ -416,6 +386,55 @@ pub struct UserOperationGasEstimation {
     pub pre_verification_gas: U256,
     pub verification_gas_limit: U256,
     pub call_gas_limit: U256,
+    #[serde(default)]
     pub paymaster_verification_gas_limit: U256,
+    #[serde(default)]
     pub paymaster_post_op_gas_limit: U256
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::test_fixtures::user_operation as uo;
+    use serde_json::json;
+
+    #[test]
+    fn serializes_without_absent_factory_and_paymaster() {
+        let value = serde_json::to_value(uo()).unwrap();
+        let object = value.as_object().unwrap();
+        for field in ["factory", "factoryData", "paymaster", "paymasterVerificationGasLimit", "paymasterPostOpGasLimit", "paymasterData"] {
+            assert!(!object.contains_key(field), "{field} should be omitted");
+        }
+        assert_eq!(value["callGasLimit"], json!("0x4623"));
+        assert_eq!(value["signature"], json!("0x"));
+    }
+
+    #[test]
+    fn serializes_paymaster_fields_when_present() {
+        let paymaster: Address = "0x0000000000000039cd5e8aE05257CE51C473ddd1".parse().unwrap();
+        let value = serde_json::to_value(
+            uo().paymaster(Some(paymaster))
+                .paymaster_verification_gas_limit(U256::from(40_000u64))
+        ).unwrap();
+        assert_eq!(value["paymaster"], json!(paymaster));
+        assert_eq!(value["paymasterVerificationGasLimit"], json!("0x9c40"));
+        assert_eq!(value["paymasterPostOpGasLimit"], json!("0x0"));
+        assert_eq!(value["paymasterData"], json!("0x"));
+    }
+
+    #[test]
+    fn deserializes_bundler_json_without_optional_fields() {
+        let uo = uo();
+        let parsed: UserOperation = serde_json::from_value(serde_json::to_value(&uo).unwrap()).unwrap();
+        assert_eq!(parsed, uo);
+        assert_eq!(parsed.paymaster, None);
+    }
+
+    #[test]
+    fn partial_conversion_keeps_absent_paymaster() {
+        let partial = UserOperationPartial::from(uo());
+        assert!(partial.factory.is_none());
+        assert!(partial.paymaster.is_none());
+        assert_eq!(UserOperation::from(partial), uo());
+    }
+}
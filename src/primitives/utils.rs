@@ -1,4 +1,8 @@
-use ethers::{types::Address, utils::to_checksum};
+use ethers::{
+    abi::AbiDecode,
+    types::{Address, Bytes, U256},
+    utils::to_checksum,
+};
 
 /// Converts address to checksum address
 pub fn as_checksum<S>(val: &Address, s: S) -> Result<S::Ok, S::Error>
@@ -15,4 +19,43 @@ pub fn get_address(buf: &[u8]) -> Option<Address> {
     } else {
         None
     }
-}
\ No newline at end of file
+}
+
+/// Human readable revert reason: the message of `Error(string)`, the code of `Panic(uint256)`,
+/// or the revert data as hex for custom errors.
+pub fn decode_revert_reason(data: &[u8]) -> String {
+    const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+    const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+
+    if data.len() >= 4 {
+        let (selector, args) = data.split_at(4);
+        if selector == ERROR_SELECTOR {
+            if let Ok(message) = String::decode(args) {
+                return message;
+            }
+        } else if selector == PANIC_SELECTOR {
+            if let Ok(code) = U256::decode(args) {
+                return format!("panic {:#04x}", code);
+            }
+        }
+    }
+    Bytes::from(data.to_vec()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::AbiEncode;
+
+    #[test]
+    fn decodes_error_and_panic_reasons() {
+        let error = [&[0x08, 0xc3, 0x79, 0xa0][..], &"not owner".to_string().encode()].concat();
+        assert_eq!(decode_revert_reason(&error), "not owner");
+
+        let panic = [&[0x4e, 0x48, 0x7b, 0x71][..], &U256::from(0x11u64).encode()].concat();
+        assert_eq!(decode_revert_reason(&panic), "panic 0x11");
+
+        assert_eq!(decode_revert_reason(&[0xde, 0xad, 0xbe, 0xef]), "0xdeadbeef");
+        assert_eq!(decode_revert_reason(&[]), "0x");
+    }
+}
@@ -0,0 +1,34 @@
+pub mod rest;
+pub mod rpc;
+
+use crate::userop_middleware::UserOpMiddleware;
+use axum::{routing::post, Router};
+use ethers::providers::Middleware;
+use std::fmt;
+use std::sync::Arc;
+use tokio::net::{TcpListener, ToSocketAddrs};
+
+/// Shared state handed to every handler. The middleware is only borrowed immutably,
+/// so one instance can serve concurrent requests.
+pub type ServerState<M> = Arc<UserOpMiddleware<M>>;
+
+pub fn router<M: Middleware + 'static + fmt::Debug + Clone>(
+    uo_middleware: ServerState<M>,
+) -> Router {
+    Router::new()
+        .route("/", post(rpc::handle_rpc::<M>))
+        .route("/rpc", post(rpc::handle_rpc::<M>))
+        .merge(rest::routes::<M>())
+        .with_state(uo_middleware)
+}
+
+pub async fn serve<M: Middleware + 'static + fmt::Debug + Clone>(
+    address: impl ToSocketAddrs,
+    uo_middleware: UserOpMiddleware<M>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    log::info!("UserOperation server listening on {}", listener.local_addr()?);
+
+    axum::serve(listener, router(Arc::new(uo_middleware))).await?;
+    Ok(())
+}
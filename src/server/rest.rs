@@ -0,0 +1,238 @@
+use super::ServerState;
+use crate::contract_call;
+use crate::erc20::{self, Erc20Operation};
+use crate::primitives::execution::Execution;
+use crate::primitives::module::{self, AccountModules, DEFAULT_FALLBACK_SELECTORS};
+use crate::primitives::user_operation::{UserOperation, UserOperationHash, UserOperationPartial};
+use crate::signing::SigningStrategy;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use ethers::{
+    providers::Middleware,
+    types::{Address, Bytes, H256, U256},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// A call to be executed by the smart account. The calldata is either given encoded in `data`,
+/// or as a `function` signature (or ABI item) with JSON `args` to be encoded by the server.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CallRequest {
+    pub to: Address,
+    #[serde(default)]
+    pub value: U256,
+    #[serde(default)]
+    pub data: Bytes,
+    #[serde(default)]
+    pub function: Option<String>,
+    #[serde(default)]
+    pub args: Vec<Value>,
+}
+
+impl TryFrom<CallRequest> for Execution {
+    type Error = ApiError;
+
+    fn try_from(call: CallRequest) -> Result<Self, Self::Error> {
+        let call_data = match &call.function {
+            Some(_) if !call.data.is_empty() => {
+                return Err(ApiError::bad_request("give either data or function, not both"));
+            },
+            Some(function) => contract_call::encode_call(function, &call.args)
+                .map_err(|e| ApiError::bad_request(format!("{:#}", e)))?,
+            None => call.data,
+        };
+        Ok(Execution::new(call.to, call.value, call_data))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BuildUserOperationRequest {
+    pub calls: Vec<CallRequest>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountModulesQuery {
+    /// Comma separated selectors whose fallback handlers are looked up.
+    pub selectors: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BuildErc20UserOperationRequest {
+    pub operations: Vec<Erc20Operation>,
+}
+
+/// Unsigned user operation together with what the client has to sign: `digest`, computed with
+/// the `signingStrategy` of the validator the operation's nonce key selects.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnsignedUserOperationResponse {
+    pub user_operation: UserOperation,
+    pub user_op_hash: UserOperationHash,
+    pub signing_strategy: SigningStrategy,
+    /// Signed as is, e.g. with `sign_hash`; for `eip191` the message prefix is already applied.
+    pub digest: H256,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubmitRequest {
+    pub user_operation: UserOperationPartial,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubmitResponse {
+    pub user_op_hash: UserOperationHash,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self {
+            status: StatusCode::BAD_GATEWAY,
+            message: format!("{:#}", err),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        log::warn!("rest request failed: {}", self.message);
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+pub fn routes<M: Middleware + 'static + fmt::Debug + Clone>() -> Router<ServerState<M>> {
+    Router::new()
+        .route("/accounts/:sender/userops", post(build_user_operation::<M>))
+        .route("/accounts/:sender/userops/erc20", post(build_erc20_user_operation::<M>))
+        .route("/accounts/:sender/modules", get(get_account_modules::<M>))
+        .route("/submit", post(submit_user_operation::<M>))
+}
+
+/// `POST /accounts/{sender}/userops`
+///
+/// Builds and estimates an unsigned user operation for `sender`, batching the calls when there
+/// is more than one. The signature is left empty; the client signs the returned `digest`, which
+/// is `userOpHash` or derived from it as `signingStrategy` says, and posts the operation back to
+/// `/submit`.
+pub async fn build_user_operation<M: Middleware + 'static + fmt::Debug + Clone>(
+    State(uo_middleware): State<ServerState<M>>,
+    Path(sender): Path<Address>,
+    Json(request): Json<BuildUserOperationRequest>,
+) -> Result<Json<UnsignedUserOperationResponse>, ApiError> {
+    if request.calls.is_empty() {
+        return Err(ApiError::bad_request("calls must not be empty"));
+    }
+    let executions = request
+        .calls
+        .into_iter()
+        .map(Execution::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let user_operation = uo_middleware.uogen_executions(sender, &executions).await?;
+    Ok(Json(unsigned_response(&uo_middleware, user_operation)?))
+}
+
+/// `POST /accounts/{sender}/userops/erc20`
+///
+/// Like `/accounts/{sender}/userops`, with the calls given as typed ERC-20 operations
+/// (`transfer`, `approve`, `transferFrom`, `permitTransferFrom`) instead of raw calldata.
+pub async fn build_erc20_user_operation<M: Middleware + 'static + fmt::Debug + Clone>(
+    State(uo_middleware): State<ServerState<M>>,
+    Path(sender): Path<Address>,
+    Json(request): Json<BuildErc20UserOperationRequest>,
+) -> Result<Json<UnsignedUserOperationResponse>, ApiError> {
+    if request.operations.is_empty() {
+        return Err(ApiError::bad_request("operations must not be empty"));
+    }
+    let executions = erc20::executions(sender, &request.operations)
+        .map_err(|e| ApiError::bad_request(format!("{:#}", e)))?;
+
+    let user_operation = uo_middleware.uogen_executions(sender, &executions).await?;
+    Ok(Json(unsigned_response(&uo_middleware, user_operation)?))
+}
+
+fn unsigned_response<M: Middleware + 'static + fmt::Debug + Clone>(
+    uo_middleware: &ServerState<M>,
+    user_operation: UserOperationPartial,
+) -> Result<UnsignedUserOperationResponse, ApiError> {
+    let user_operation = UserOperation::from(user_operation);
+    let user_op_hash = uo_middleware.uo_hash(&user_operation)?;
+    let signing_strategy = uo_middleware.signing_strategy(&user_operation);
+    let digest = uo_middleware.signing_digest(&user_operation)?;
+    Ok(UnsignedUserOperationResponse {
+        user_operation,
+        user_op_hash,
+        signing_strategy,
+        digest,
+    })
+}
+
+/// `GET /accounts/{sender}/modules?selectors=0x150b7a02,...`
+///
+/// Returns the validators, executors, hook and fallback handlers installed on `sender`. Without
+/// `selectors` the ERC-721 and ERC-1155 receiver callbacks are looked up.
+pub async fn get_account_modules<M: Middleware + 'static + fmt::Debug + Clone>(
+    State(uo_middleware): State<ServerState<M>>,
+    Path(sender): Path<Address>,
+    Query(query): Query<AccountModulesQuery>,
+) -> Result<Json<AccountModules>, ApiError> {
+    let selectors = match query.selectors.as_deref() {
+        Some(selectors) => selectors
+            .split(',')
+            .filter(|selector| !selector.trim().is_empty())
+            .map(module::parse_selector)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| ApiError::bad_request(format!("{:#}", e)))?,
+        None => DEFAULT_FALLBACK_SELECTORS.to_vec(),
+    };
+
+    let modules = uo_middleware.inspect_account(sender, &selectors).await?;
+    Ok(Json(modules))
+}
+
+/// `POST /submit`
+///
+/// Forwards a user operation signed by the client to the bundler.
+pub async fn submit_user_operation<M: Middleware + 'static + fmt::Debug + Clone>(
+    State(uo_middleware): State<ServerState<M>>,
+    Json(request): Json<SubmitRequest>,
+) -> Result<Json<SubmitResponse>, ApiError> {
+    let signed = request
+        .user_operation
+        .signature
+        .as_ref()
+        .is_some_and(|signature| !signature.is_empty());
+    if !signed {
+        return Err(ApiError::bad_request("user operation is not signed"));
+    }
+
+    let sent = uo_middleware
+        .send_user_operation(&request.user_operation)
+        .await?;
+
+    Ok(Json(SubmitResponse {
+        user_op_hash: sent,
+    }))
+}
@@ -0,0 +1,379 @@
+use super::ServerState;
+use crate::erc20::{Erc20Operation, Erc20Permit};
+use crate::primitives::execution::Execution;
+use crate::primitives::module::{ModuleType, DEFAULT_FALLBACK_SELECTORS};
+use crate::primitives::user_operation::{UserOperation, UserOperationHash, UserOperationPartial};
+use crate::userop_middleware::{JsonRpcError, DEFAULT_RECEIPT_POLL_INTERVAL, DEFAULT_RECEIPT_TIMEOUT};
+use axum::{extract::State, http::{header::AUTHORIZATION, HeaderMap}, Json};
+use ethers::{
+    providers::Middleware,
+    types::{Address, Bytes, U256, U64},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::Duration;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const SERVER_ERROR: i64 = -32000;
+pub const UNAUTHORIZED: i64 = -32001;
+
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    #[serde(default)]
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JsonRpcError>,
+}
+
+impl RpcResponse {
+    pub fn result(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    pub fn error(id: Value, error: JsonRpcError) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+fn rpc_error(code: i64, message: impl Into<String>) -> JsonRpcError {
+    JsonRpcError {
+        code,
+        message: message.into(),
+        data: None,
+    }
+}
+
+fn server_error(err: anyhow::Error) -> JsonRpcError {
+    rpc_error(SERVER_ERROR, format!("{:#}", err))
+}
+
+fn to_result<T: Serialize>(value: T) -> Result<Value, JsonRpcError> {
+    serde_json::to_value(value).map_err(|e| server_error(e.into()))
+}
+
+/// Decodes positional `params` into a tuple, e.g. `(Address, U256)` for `[to, value]`.
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, JsonRpcError> {
+    let params = if params.is_null() { json!([]) } else { params };
+    serde_json::from_value(params).map_err(|e| rpc_error(INVALID_PARAMS, e.to_string()))
+}
+
+/// The server wallet only signs over JSON-RPC for callers holding the signing token, and only
+/// for its own account, so a caller cannot have it sign for an account it chose.
+fn check_signing<M: Middleware + 'static + fmt::Debug + Clone>(
+    uo_middleware: &ServerState<M>,
+    user_operation: &UserOperationPartial,
+    signing_authorized: bool,
+) -> Result<(), JsonRpcError> {
+    if !signing_authorized {
+        return Err(rpc_error(UNAUTHORIZED, "signing needs RPC_SIGNING_TOKEN as the bearer token"));
+    }
+    if user_operation.sender != Some(uo_middleware.sender) {
+        return Err(rpc_error(
+            INVALID_PARAMS,
+            format!("only user operations of {:?} are signed", uo_middleware.sender),
+        ));
+    }
+    Ok(())
+}
+
+/// ERC-7677 `[userOp, entryPoint, chainId, context]`; the context is optional and unused.
+fn parse_paymaster_params(params: Value) -> Result<(UserOperationPartial, Address, U64), JsonRpcError> {
+    match parse_params::<(UserOperationPartial, Address, U64)>(params.clone()) {
+        Ok(params) => Ok(params),
+        Err(_) => {
+            let (user_operation, entry_point, chain_id, _context): (UserOperationPartial, Address, U64, Value) =
+                parse_params(params)?;
+            Ok((user_operation, entry_point, chain_id))
+        }
+    }
+}
+
+/// Entry point for `POST /` and `POST /rpc`. Accepts a single JSON-RPC request or a batch.
+/// The signing methods also need the `Authorization: Bearer` header to carry the configured
+/// signing token.
+pub async fn handle_rpc<M: Middleware + 'static + fmt::Debug + Clone>(
+    State(uo_middleware): State<ServerState<M>>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Json<Value> {
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let signing_authorized = uo_middleware.authorizes_rpc_signing(bearer);
+    match body {
+        Value::Array(requests) => {
+            if requests.is_empty() {
+                let response = RpcResponse::error(Value::Null, rpc_error(INVALID_REQUEST, "empty batch"));
+                return Json(json!(response));
+            }
+            let mut responses = Vec::with_capacity(requests.len());
+            for request in requests {
+                responses.push(handle_single(&uo_middleware, request, signing_authorized).await);
+            }
+            Json(json!(responses))
+        }
+        request => Json(json!(handle_single(&uo_middleware, request, signing_authorized).await)),
+    }
+}
+
+async fn handle_single<M: Middleware + 'static + fmt::Debug + Clone>(
+    uo_middleware: &ServerState<M>,
+    request: Value,
+    signing_authorized: bool,
+) -> RpcResponse {
+    let request: RpcRequest = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(e) => return RpcResponse::error(Value::Null, rpc_error(PARSE_ERROR, e.to_string())),
+    };
+
+    if request.jsonrpc != "2.0" {
+        return RpcResponse::error(request.id, rpc_error(INVALID_REQUEST, "jsonrpc must be \"2.0\""));
+    }
+
+    log::info!("rpc request: {}", request.method);
+    match dispatch(uo_middleware, &request.method, request.params, signing_authorized).await {
+        Ok(result) => RpcResponse::result(request.id, result),
+        Err(error) => {
+            log::warn!("rpc {} failed: {}", request.method, error.message);
+            RpcResponse::error(request.id, error)
+        }
+    }
+}
+
+async fn dispatch<M: Middleware + 'static + fmt::Debug + Clone>(
+    uo_middleware: &ServerState<M>,
+    method: &str,
+    params: Value,
+    signing_authorized: bool,
+) -> Result<Value, JsonRpcError> {
+    match method {
+        "uo_supportedEntryPoints" => to_result(vec![uo_middleware.supported_entry_point()]),
+        "uo_chainId" => to_result(U64::from(uo_middleware.chain_id())),
+        "uo_buildSendEth" => {
+            let (to_address, value): (Address, U256) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_send_eth(to_address, value)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_buildCall" => {
+            let (target, value, abi_or_signature, args): (Address, U256, String, Vec<Value>) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_call(target, value, &abi_or_signature, &args)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_buildExecuteBatch" => {
+            let (executions,): (Vec<Execution>,) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_execute_batch(&executions)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_buildParallel" => {
+            let (batches,): (Vec<Vec<Execution>>,) = parse_params(params)?;
+            let user_operations = uo_middleware
+                .uogen_parallel(uo_middleware.sender, &batches)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operations)
+        }
+        "uo_buildErc20Transfer" => {
+            let (token, to_address, amount): (Address, Address, U256) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_erc20_transfer(token, to_address, amount)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_buildErc20Approve" => {
+            let (token, spender, amount): (Address, Address, U256) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_erc20_approve(token, spender, amount)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_buildErc20TransferFrom" => {
+            let (token, from_address, to_address, amount): (Address, Address, Address, U256) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_erc20_transfer_from(token, from_address, to_address, amount)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_buildErc20PermitTransfer" => {
+            let (token, permit, to_address): (Address, Erc20Permit, Address) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_erc20_permit_transfer(token, permit, to_address)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_buildErc20Batch" => {
+            let (operations,): (Vec<Erc20Operation>,) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_erc20(uo_middleware.sender, &operations)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_buildInstallModule" => {
+            let (module_type, module, init_data): (ModuleType, Address, Bytes) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_install_module(module_type, module, init_data)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_buildUninstallModule" => {
+            let (module_type, module, de_init_data): (ModuleType, Address, Bytes) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .uogen_uninstall_module(module_type, module, de_init_data)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "uo_isModuleInstalled" => {
+            let (account, module_type, module, additional_context): (Address, ModuleType, Address, Bytes) =
+                parse_params(params)?;
+            let installed = uo_middleware
+                .is_module_installed(account, module_type, module, additional_context)
+                .await
+                .map_err(server_error)?;
+            to_result(installed)
+        }
+        "uo_getAccountModules" => {
+            let (account, selectors): (Address, Option<Vec<Bytes>>) = match parse_params::<(Address,)>(params.clone()) {
+                Ok((account,)) => (account, None),
+                Err(_) => parse_params(params)?,
+            };
+            let selectors = match selectors {
+                Some(selectors) => selectors
+                    .iter()
+                    .map(|selector| <[u8; 4]>::try_from(selector.as_ref()))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| rpc_error(INVALID_PARAMS, "selectors must be 4 bytes"))?,
+                None => DEFAULT_FALLBACK_SELECTORS.to_vec(),
+            };
+            let modules = uo_middleware
+                .inspect_account(account, &selectors)
+                .await
+                .map_err(server_error)?;
+            to_result(modules)
+        }
+        "uo_gasFees" => {
+            let fees = uo_middleware.estimate_fees().await.map_err(server_error)?;
+            to_result(fees)
+        }
+        "uo_estimateUserOperationGas" => {
+            let (user_operation,): (UserOperationPartial,) = parse_params(params)?;
+            let estimated = uo_middleware
+                .estimate_user_operation_gas(&user_operation)
+                .await
+                .map_err(server_error)?;
+            to_result(estimated)
+        }
+        "uo_signUserOperation" => {
+            let (user_operation,): (UserOperationPartial,) = parse_params(params)?;
+            check_signing(uo_middleware, &user_operation, signing_authorized)?;
+            let signed = uo_middleware
+                .sign_uo(UserOperation::from(user_operation))
+                .await
+                .map_err(server_error)?;
+            to_result(signed)
+        }
+        "uo_sendUserOperation" => {
+            let (user_operation,): (UserOperationPartial,) = parse_params(params)?;
+            let sent = uo_middleware
+                .send_user_operation(&user_operation)
+                .await
+                .map_err(server_error)?;
+            to_result(sent)
+        }
+        "uo_signAndSendUserOperation" => {
+            let (user_operation,): (UserOperationPartial,) = parse_params(params)?;
+            check_signing(uo_middleware, &user_operation, signing_authorized)?;
+            let sent = uo_middleware
+                .sign_and_send(user_operation)
+                .await
+                .map_err(server_error)?;
+            to_result(sent)
+        }
+        "uo_getUserOperationReceipt" => {
+            let (user_operation_hash,): (UserOperationHash,) = parse_params(params)?;
+            let receipt = uo_middleware
+                .get_user_operation_receipt(&user_operation_hash)
+                .await
+                .map_err(server_error)?;
+            to_result(receipt)
+        }
+        "uo_waitForUserOperationReceipt" => {
+            let (user_operation_hash, timeout): (UserOperationHash, Option<u64>) =
+                match parse_params::<(UserOperationHash,)>(params.clone()) {
+                    Ok((user_operation_hash,)) => (user_operation_hash, None),
+                    Err(_) => parse_params(params)?,
+                };
+            let timeout = timeout.map_or(DEFAULT_RECEIPT_TIMEOUT, Duration::from_secs);
+            let receipt = uo_middleware
+                .wait_for_receipt(&user_operation_hash, timeout, DEFAULT_RECEIPT_POLL_INTERVAL)
+                .await
+                .map_err(server_error)?;
+            to_result(receipt)
+        }
+        "uo_getUserOperationByHash" => {
+            let (user_operation_hash,): (UserOperationHash,) = parse_params(params)?;
+            let user_operation = uo_middleware
+                .get_user_operation_by_hash(&user_operation_hash)
+                .await
+                .map_err(server_error)?;
+            to_result(user_operation)
+        }
+        "pm_getPaymasterStubData" => {
+            let (user_operation, entry_point, chain_id) = parse_paymaster_params(params)?;
+            let stub = uo_middleware
+                .sponsor_stub_data(&user_operation, entry_point, chain_id.as_u64())
+                .map_err(server_error)?;
+            to_result(stub)
+        }
+        "pm_getPaymasterData" => {
+            let (user_operation, entry_point, chain_id) = parse_paymaster_params(params)?;
+            let data = uo_middleware
+                .sponsor_user_operation(&user_operation, entry_point, chain_id.as_u64())
+                .await
+                .map_err(server_error)?;
+            to_result(data)
+        }
+        _ => Err(rpc_error(METHOD_NOT_FOUND, format!("method {} not found", method))),
+    }
+}
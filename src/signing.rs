@@ -0,0 +1,346 @@
+use crate::errors::Uint128Overflow;
+use crate::primitives::packed_user_operation::PackedUserOperation;
+use crate::primitives::user_operation::UserOperation;
+use ethers::{
+    abi::{self, Token},
+    signers::LocalWallet,
+    types::{Address, Signature, H256, U256},
+    utils::{hash_message, keccak256},
+};
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// `PackedUserOperation` EIP-712 type of the ERC-4337 v0.8 EntryPoint; its `keccak256` is the
+/// type hash of the struct signed by [`SigningStrategy::Eip712`].
+pub const PACKED_USER_OPERATION_TYPE: &str = "PackedUserOperation(address sender,uint256 nonce,bytes initCode,bytes callData,bytes32 accountGasLimits,uint256 preVerificationGas,bytes32 gasFees,bytes paymasterAndData)";
+
+const EIP712_DOMAIN_TYPE: &str = "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)";
+
+/// What the wallet signs for a user operation. It must be the digest the validator module
+/// recovers the signer from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SigningStrategy {
+    /// The user operation hash itself, for validators calling `ecrecover(userOpHash, ...)`.
+    RawHash,
+    /// `personal_sign` of the user operation hash, with the EIP-191 message prefix.
+    #[default]
+    Eip191,
+    /// EIP-712 typed data of the packed user operation: `keccak256(0x1901 ‖ domainSeparator ‖
+    /// keccak256(typeHash ‖ packed fields))`, with [`PACKED_USER_OPERATION_TYPE`] as the type and
+    /// [`Eip712Domain`] as the domain. The default domain is the v0.8 EntryPoint's, name
+    /// `ERC4337`, version `1`, with the entry point as verifying contract. The v0.7 EntryPoint
+    /// has no EIP-712 domain, so with it only validators written for the configured domain
+    /// verify this digest.
+    Eip712,
+}
+
+impl SigningStrategy {
+    /// Digest signed for `user_operation` on `entry_point` and `chain_id`, in the default EIP-712
+    /// domain. Fails when a gas limit or fee of the operation does not fit in `uint128`.
+    pub fn digest(
+        &self,
+        user_operation: &UserOperation,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> Result<H256, Uint128Overflow> {
+        self.digest_in(user_operation, entry_point, chain_id, &Eip712Domain::default())
+    }
+
+    /// Like [`Self::digest`], with `domain` as the EIP-712 domain; the other strategies ignore it.
+    pub fn digest_in(
+        &self,
+        user_operation: &UserOperation,
+        entry_point: Address,
+        chain_id: u64,
+        domain: &Eip712Domain,
+    ) -> Result<H256, Uint128Overflow> {
+        Ok(match self {
+            SigningStrategy::RawHash => user_operation.hash(&entry_point, &U256::from(chain_id))?.0,
+            SigningStrategy::Eip191 => {
+                hash_message(user_operation.hash(&entry_point, &U256::from(chain_id))?.0.as_bytes())
+            },
+            SigningStrategy::Eip712 => {
+                let packed = PackedUserOperation::try_from(user_operation.clone())?;
+                let struct_hash = keccak256(
+                    [&keccak256(PACKED_USER_OPERATION_TYPE)[..], &packed.pack_without_signature()].concat(),
+                );
+                let digest = [
+                    &[0x19, 0x01][..],
+                    &domain.separator(entry_point, chain_id),
+                    &struct_hash,
+                ]
+                .concat();
+                H256::from(keccak256(digest))
+            },
+        })
+    }
+
+    /// 65 bytes `r ++ s ++ v` signature of [`Self::digest`] by `wallet`.
+    pub fn sign(
+        &self,
+        wallet: &LocalWallet,
+        user_operation: &UserOperation,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> anyhow::Result<Signature> {
+        Ok(wallet.sign_hash(self.digest(user_operation, entry_point, chain_id)?)?)
+    }
+}
+
+/// `EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)` of
+/// [`SigningStrategy::Eip712`]. The chain id is always the one the operation is built for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Eip712Domain {
+    pub name: String,
+    pub version: String,
+    /// `None` for the entry point the operation is sent to.
+    pub verifying_contract: Option<Address>,
+}
+
+impl Default for Eip712Domain {
+    /// The v0.8 EntryPoint's domain.
+    fn default() -> Self {
+        Self {
+            name: "ERC4337".to_string(),
+            version: "1".to_string(),
+            verifying_contract: None,
+        }
+    }
+}
+
+impl Eip712Domain {
+    pub fn separator(&self, entry_point: Address, chain_id: u64) -> [u8; 32] {
+        keccak256(abi::encode(&[
+            Token::FixedBytes(keccak256(EIP712_DOMAIN_TYPE).to_vec()),
+            Token::FixedBytes(keccak256(&self.name).to_vec()),
+            Token::FixedBytes(keccak256(&self.version).to_vec()),
+            Token::Uint(U256::from(chain_id)),
+            Token::Address(self.verifying_contract.unwrap_or(entry_point)),
+        ]))
+    }
+}
+
+impl FromStr for Eip712Domain {
+    type Err = anyhow::Error;
+
+    /// `name:version`, or `name:version:verifyingContract` for a contract other than the entry
+    /// point.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(':').map(str::trim);
+        match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(name), Some(version), verifying_contract, None) => Ok(Self {
+                name: name.to_string(),
+                version: version.to_string(),
+                verifying_contract: verifying_contract.map(str::parse).transpose()?,
+            }),
+            _ => Err(anyhow::anyhow!("expected name:version[:verifyingContract], got {:?}", s)),
+        }
+    }
+}
+
+impl FromStr for SigningStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "raw" | "rawhash" | "raw-hash" => Ok(SigningStrategy::RawHash),
+            "eip191" | "personal" => Ok(SigningStrategy::Eip191),
+            "eip712" | "typed" => Ok(SigningStrategy::Eip712),
+            _ => Err(anyhow::anyhow!("unknown signing strategy {:?}, expected rawHash, eip191 or eip712", s)),
+        }
+    }
+}
+
+impl fmt::Display for SigningStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SigningStrategy::RawHash => "rawHash",
+            SigningStrategy::Eip191 => "eip191",
+            SigningStrategy::Eip712 => "eip712",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Signing strategy of each validator module, `default` for the ones not listed, and the
+/// EIP-712 domain of the validators that do not use the default one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SigningStrategies {
+    pub default: SigningStrategy,
+    pub validators: HashMap<Address, SigningStrategy>,
+    pub domains: HashMap<Address, Eip712Domain>,
+}
+
+impl SigningStrategies {
+    pub fn new(default: SigningStrategy) -> Self {
+        Self {
+            default,
+            validators: HashMap::new(),
+            domains: HashMap::new(),
+        }
+    }
+
+    pub fn with_validator(mut self, validator: Address, strategy: SigningStrategy) -> Self {
+        self.validators.insert(validator, strategy);
+        self
+    }
+
+    pub fn for_validator(&self, validator: Address) -> SigningStrategy {
+        self.validators.get(&validator).copied().unwrap_or(self.default)
+    }
+
+    pub fn with_eip712_domain(mut self, validator: Address, domain: Eip712Domain) -> Self {
+        self.domains.insert(validator, domain);
+        self
+    }
+
+    /// Digest `validator` verifies for `user_operation`, with its strategy and EIP-712 domain.
+    pub fn digest(
+        &self,
+        validator: Address,
+        user_operation: &UserOperation,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> Result<H256, Uint128Overflow> {
+        let domain = self.domains.get(&validator).cloned().unwrap_or_default();
+        self.for_validator(validator).digest_in(user_operation, entry_point, chain_id, &domain)
+    }
+
+    /// Adds the validators of a comma-separated `validator=strategy` list, e.g.
+    /// `0x...=raw,0x...=eip712`.
+    pub fn with_validators(mut self, list: &str) -> anyhow::Result<Self> {
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (validator, strategy) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected validator=strategy, got {:?}", entry))?;
+            self.validators.insert(validator.trim().parse()?, strategy.trim().parse()?);
+        }
+        Ok(self)
+    }
+
+    /// Adds the EIP-712 domains of a comma-separated `validator=name:version[:verifyingContract]`
+    /// list, e.g. `0x...=MyValidator:1`.
+    pub fn with_eip712_domains(mut self, list: &str) -> anyhow::Result<Self> {
+        for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (validator, domain) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected validator=domain, got {:?}", entry))?;
+            self.domains.insert(validator.trim().parse()?, domain.parse()?);
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::{
+        primitives::{Address as a_Address, Bytes as a_Bytes, FixedBytes, U256 as a_U256},
+        sol,
+        sol_types::{eip712_domain, SolStruct},
+    };
+    use crate::primitives::test_fixtures::{entry_point, user_operation as uo, wallet, SEPOLIA_CHAIN_ID};
+    use ethers::signers::Signer;
+
+    sol! {
+        struct PackedUserOperation {
+            address sender;
+            uint256 nonce;
+            bytes initCode;
+            bytes callData;
+            bytes32 accountGasLimits;
+            uint256 preVerificationGas;
+            bytes32 gasFees;
+            bytes paymasterAndData;
+        }
+    }
+
+    #[tokio::test]
+    async fn raw_and_eip191_sign_the_user_operation_hash() {
+        let hash = uo().hash(&entry_point(), &U256::from(SEPOLIA_CHAIN_ID)).unwrap().0;
+
+        let raw = SigningStrategy::RawHash.sign(&wallet(), &uo(), entry_point(), SEPOLIA_CHAIN_ID).unwrap();
+        assert_eq!(raw.recover(hash).unwrap(), wallet().address());
+
+        let personal = SigningStrategy::Eip191.sign(&wallet(), &uo(), entry_point(), SEPOLIA_CHAIN_ID).unwrap();
+        assert_eq!(personal, wallet().sign_message(hash.as_bytes()).await.unwrap());
+    }
+
+    fn typed() -> PackedUserOperation {
+        let packed = crate::primitives::packed_user_operation::PackedUserOperation::try_from(uo()).unwrap();
+        PackedUserOperation {
+            sender: a_Address::from(packed.sender.0),
+            nonce: a_U256::from_limbs(packed.nonce.0),
+            initCode: a_Bytes::from(packed.init_code.to_vec()),
+            callData: a_Bytes::from(packed.call_data.to_vec()),
+            accountGasLimits: FixedBytes::from(packed.account_gas_limits.0),
+            preVerificationGas: a_U256::from_limbs(packed.pre_verification_gas.0),
+            gasFees: FixedBytes::from(packed.gas_fees.0),
+            paymasterAndData: a_Bytes::from(packed.paymaster_and_data.to_vec()),
+        }
+    }
+
+    #[test]
+    fn eip712_digest_matches_typed_data_hash() {
+        let domain = eip712_domain! {
+            name: "ERC4337",
+            version: "1",
+            chain_id: SEPOLIA_CHAIN_ID,
+            verifying_contract: a_Address::from(entry_point().0),
+        };
+        let digest = SigningStrategy::Eip712.digest(&uo(), entry_point(), SEPOLIA_CHAIN_ID).unwrap();
+        assert_eq!(digest.0, typed().eip712_signing_hash(&domain).0);
+    }
+
+    #[test]
+    fn picks_the_strategy_of_the_validator() {
+        let validator: Address = "0x0000000000000000000000000000000000007579".parse().unwrap();
+        let strategies = SigningStrategies::default()
+            .with_validators(&format!("{:?}=rawHash, ", validator))
+            .unwrap();
+        assert_eq!(strategies.for_validator(validator), SigningStrategy::RawHash);
+        assert_eq!(strategies.for_validator(Address::zero()), SigningStrategy::Eip191);
+        assert!(SigningStrategies::default().with_validators(&format!("{:?}=bogus", validator)).is_err());
+        assert_eq!("EIP712".parse::<SigningStrategy>().unwrap().to_string(), "eip712");
+    }
+
+    #[test]
+    fn eip712_digest_uses_the_validator_domain() {
+        let validator: Address = "0x0000000000000000000000000000000000007579".parse().unwrap();
+        let verifying_contract: Address = "0x000000000000000000000000000000000000beef".parse().unwrap();
+        let strategies = SigningStrategies::new(SigningStrategy::Eip712)
+            .with_eip712_domains(&format!("{:?}=MyValidator:2:{:?}", validator, verifying_contract))
+            .unwrap();
+
+        let domain = eip712_domain! {
+            name: "MyValidator",
+            version: "2",
+            chain_id: SEPOLIA_CHAIN_ID,
+            verifying_contract: a_Address::from(verifying_contract.0),
+        };
+        let digest = strategies.digest(validator, &uo(), entry_point(), SEPOLIA_CHAIN_ID).unwrap();
+        assert_eq!(digest.0, typed().eip712_signing_hash(&domain).0);
+        assert_eq!(
+            strategies.digest(Address::zero(), &uo(), entry_point(), SEPOLIA_CHAIN_ID).unwrap(),
+            SigningStrategy::Eip712.digest(&uo(), entry_point(), SEPOLIA_CHAIN_ID).unwrap(),
+        );
+
+        assert_eq!("ERC4337:1".parse::<Eip712Domain>().unwrap(), Eip712Domain::default());
+        assert!("ERC4337".parse::<Eip712Domain>().is_err());
+        assert!("a:1:0x01:extra".parse::<Eip712Domain>().is_err());
+    }
+
+    #[test]
+    fn display_parse_and_serde_use_the_same_names() {
+        for strategy in [SigningStrategy::RawHash, SigningStrategy::Eip191, SigningStrategy::Eip712] {
+            let name = strategy.to_string();
+            assert_eq!(name.parse::<SigningStrategy>().unwrap(), strategy);
+            assert_eq!(serde_json::to_value(strategy).unwrap(), serde_json::json!(name));
+            assert_eq!(serde_json::from_value::<SigningStrategy>(serde_json::json!(name)).unwrap(), strategy);
+        }
+        assert_eq!(SigningStrategy::RawHash.to_string(), "rawHash");
+    }
+}
@@ -0,0 +1,392 @@
+use crate::errors::{SponsorshipRejected, SponsorshipRejection};
+use crate::primitives::execution::decode_execute;
+use crate::primitives::mode_code::CallType;
+use crate::primitives::user_operation::UserOperation;
+use ethers::types::{Address, Bytes, U256};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Calls to `target` that are sponsored; an empty `selectors` allows every function.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AllowedCall {
+    pub target: Address,
+    #[serde(default)]
+    pub selectors: Vec<Bytes>,
+}
+
+/// Unix timestamps `[start, end)` during which user operations are sponsored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeWindow {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Rules a user operation has to pass to be sponsored. Absent rules do not restrict anything.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SponsorshipPolicy {
+    /// How long a paymaster signature stays valid.
+    pub validity_seconds: u64,
+    /// Shown to wallets as the sponsor.
+    pub sponsor_name: Option<String>,
+    /// Gas per sender and UTC day, counted as the sum of all gas limits of the operations. It is
+    /// charged when the paymaster data is signed, whether or not the operation is ever included,
+    /// and it is tracked per sender only, so every new counterfactual sender starts with a full
+    /// budget. `allowed_factories` narrows which deployments are sponsored, not how many.
+    pub daily_gas_budget: Option<U256>,
+    /// Calls the account may make, decoded from its `execute` call data.
+    pub allowed_calls: Option<Vec<AllowedCall>>,
+    pub allow_delegate_call: bool,
+    /// Factories an operation may deploy its sender with; operations of deployed accounts pass.
+    pub allowed_factories: Option<Vec<Address>>,
+    pub max_fee_per_gas: Option<U256>,
+    /// Sponsoring only happens inside one of these; empty means always.
+    pub time_windows: Vec<TimeWindow>,
+}
+
+impl Default for SponsorshipPolicy {
+    fn default() -> Self {
+        Self {
+            validity_seconds: 600,
+            sponsor_name: None,
+            daily_gas_budget: None,
+            allowed_calls: None,
+            allow_delegate_call: false,
+            allowed_factories: None,
+            max_fee_per_gas: None,
+            time_windows: Vec::new(),
+        }
+    }
+}
+
+/// Validity window of an approved sponsorship, as signed into the paymaster data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SponsorshipGrant {
+    pub valid_until: u64,
+    pub valid_after: u64,
+    /// Gas charged to the sender's daily budget.
+    pub gas: U256,
+}
+
+/// Evaluates a [`SponsorshipPolicy`] and keeps the gas each sender was sponsored today.
+#[derive(Debug, Default)]
+pub struct SponsorshipEngine {
+    pub policy: SponsorshipPolicy,
+    spent: Mutex<HashMap<Address, (u64, U256)>>,
+}
+
+impl SponsorshipEngine {
+    pub fn new(policy: SponsorshipPolicy) -> Self {
+        Self {
+            policy,
+            spent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the calls, fees and time of a user operation whose gas limits are not final yet,
+    /// e.g. for stub data; the budget is only checked for not being used up.
+    pub fn pre_check(&self, user_operation: &UserOperation, now: u64) -> Result<(), SponsorshipRejected> {
+        let mut rejections = self.rule_rejections(user_operation, now);
+        let remaining = self.remaining_budget(user_operation.sender, now);
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            rejections.push(SponsorshipRejection::DailyGasBudgetExceeded(
+                user_operation.sender,
+                operation_gas(user_operation),
+                U256::zero(),
+            ));
+        }
+        reject_if_any(rejections)
+    }
+
+    /// Checks every rule and charges the operation's gas to the sender's daily budget. The charge
+    /// is final: it is not refunded when the signed operation is never sent.
+    pub fn approve(&self, user_operation: &UserOperation, now: u64) -> Result<SponsorshipGrant, SponsorshipRejected> {
+        reject_if_any(self.rule_rejections(user_operation, now))?;
+
+        let gas = operation_gas(user_operation);
+        if let Some(budget) = self.policy.daily_gas_budget {
+            let mut spent = self.spent.lock();
+            let day = now / SECONDS_PER_DAY;
+            let entry = spent.entry(user_operation.sender).or_insert((day, U256::zero()));
+            if entry.0 != day {
+                *entry = (day, U256::zero());
+            }
+            let remaining = budget.saturating_sub(entry.1);
+            if gas > remaining {
+                return Err(SponsorshipRejected(vec![SponsorshipRejection::DailyGasBudgetExceeded(
+                    user_operation.sender,
+                    gas,
+                    remaining,
+                )]));
+            }
+            entry.1 = entry.1.saturating_add(gas);
+        }
+
+        // The signature must not outlive the time window it was granted in.
+        let mut valid_until = now + self.policy.validity_seconds;
+        if let Some(window) = self.active_window(now) {
+            valid_until = valid_until.min(window.end);
+        }
+        Ok(SponsorshipGrant {
+            valid_until,
+            valid_after: 0,
+            gas,
+        })
+    }
+
+    /// Gas `sender` can still be sponsored today, `None` without a budget.
+    pub fn remaining_budget(&self, sender: Address, now: u64) -> Option<U256> {
+        let budget = self.policy.daily_gas_budget?;
+        let spent = match self.spent.lock().get(&sender) {
+            Some((day, spent)) if *day == now / SECONDS_PER_DAY => *spent,
+            _ => U256::zero(),
+        };
+        Some(budget.saturating_sub(spent))
+    }
+
+    fn active_window(&self, now: u64) -> Option<&TimeWindow> {
+        self.policy
+            .time_windows
+            .iter()
+            .find(|window| window.start <= now && now < window.end)
+    }
+
+    fn rule_rejections(&self, user_operation: &UserOperation, now: u64) -> Vec<SponsorshipRejection> {
+        let mut rejections = Vec::new();
+
+        // The EntryPoint packs the limits into uint128 halves; larger ones are not a valid operation.
+        for (field, limit) in gas_limits(user_operation) {
+            if limit > U256::from(u128::MAX) {
+                rejections.push(SponsorshipRejection::GasLimitTooHigh(field.to_string(), limit));
+            }
+        }
+        if !self.policy.time_windows.is_empty() && self.active_window(now).is_none() {
+            rejections.push(SponsorshipRejection::OutsideTimeWindow);
+        }
+        if let Some(allowed_factories) = &self.policy.allowed_factories {
+            let factory = user_operation.factory;
+            if !factory.is_zero() && !allowed_factories.contains(&factory) {
+                rejections.push(SponsorshipRejection::FactoryNotAllowed(factory));
+            }
+        }
+        if let Some(max_fee_per_gas) = self.policy.max_fee_per_gas {
+            if user_operation.max_fee_per_gas > max_fee_per_gas {
+                rejections.push(SponsorshipRejection::MaxFeeTooHigh(user_operation.max_fee_per_gas, max_fee_per_gas));
+            }
+        }
+        rejections.extend(self.call_rejections(&user_operation.call_data));
+        rejections
+    }
+
+    fn call_rejections(&self, call_data: &Bytes) -> Vec<SponsorshipRejection> {
+        // Deployment-only operations make no call.
+        if call_data.is_empty() {
+            return Vec::new();
+        }
+        let (mode, executions) = match decode_execute(call_data) {
+            Ok(decoded) => decoded,
+            Err(err) => return vec![SponsorshipRejection::UndecodableCallData(err.to_string())],
+        };
+
+        let mut rejections = Vec::new();
+        if mode.call_type == CallType::DelegateCall && !self.policy.allow_delegate_call {
+            rejections.push(SponsorshipRejection::DelegateCall);
+        }
+        let allowed_calls = match &self.policy.allowed_calls {
+            Some(allowed_calls) => allowed_calls,
+            None => return rejections,
+        };
+        for execution in executions {
+            let allowed = match allowed_calls.iter().find(|allowed| allowed.target == execution.target) {
+                Some(allowed) => allowed,
+                None => {
+                    rejections.push(SponsorshipRejection::TargetNotAllowed(execution.target));
+                    continue;
+                },
+            };
+            if allowed.selectors.is_empty() {
+                continue;
+            }
+            let selector = execution.selector().map(|selector| Bytes::from(selector.to_vec())).unwrap_or_default();
+            if !allowed.selectors.contains(&selector) {
+                rejections.push(SponsorshipRejection::SelectorNotAllowed(execution.target, selector));
+            }
+        }
+        rejections
+    }
+}
+
+/// Most gas the operation can use, the sum of its gas limits. Saturates instead of overflowing
+/// on limits the policy rejects anyway.
+pub fn operation_gas(user_operation: &UserOperation) -> U256 {
+    gas_limits(user_operation)
+        .into_iter()
+        .fold(U256::zero(), |total, (_, limit)| total.saturating_add(limit))
+}
+
+fn gas_limits(user_operation: &UserOperation) -> [(&'static str, U256); 5] {
+    [
+        ("callGasLimit", user_operation.call_gas_limit),
+        ("verificationGasLimit", user_operation.verification_gas_limit),
+        ("preVerificationGas", user_operation.pre_verification_gas),
+        ("paymasterVerificationGasLimit", user_operation.paymaster_verification_gas_limit),
+        ("paymasterPostOpGasLimit", user_operation.paymaster_post_op_gas_limit),
+    ]
+}
+
+fn reject_if_any(rejections: Vec<SponsorshipRejection>) -> Result<(), SponsorshipRejected> {
+    if rejections.is_empty() {
+        Ok(())
+    } else {
+        Err(SponsorshipRejected(rejections))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::execution::{encode_batch, Execution, EXECUTE_SELECTOR};
+    use crate::primitives::mode_code::ModeCode;
+    use ethers::abi::{self, Token};
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn token() -> Address {
+        Address::from_low_u64_be(0x20)
+    }
+
+    fn execute(executions: &[Execution]) -> Bytes {
+        let args = abi::encode(&[
+            Token::FixedBytes(ModeCode::batch().encode().to_vec()),
+            Token::Bytes(encode_batch(executions).to_vec()),
+        ]);
+        [&EXECUTE_SELECTOR[..], &args].concat().into()
+    }
+
+    fn uo(executions: &[Execution]) -> UserOperation {
+        UserOperation::default()
+            .sender(Address::from_low_u64_be(0x10))
+            .call_data(execute(executions))
+            .call_gas_limit(U256::from(50_000u64))
+            .verification_gas_limit(U256::from(40_000u64))
+            .pre_verification_gas(U256::from(10_000u64))
+            .max_fee_per_gas(U256::from(2_000_000_000u64))
+    }
+
+    fn transfer() -> Execution {
+        Execution::new(token(), U256::zero(), "0xa9059cbb0000".parse().unwrap())
+    }
+
+    #[test]
+    fn rejects_calls_outside_the_allow_list() {
+        let engine = SponsorshipEngine::new(SponsorshipPolicy {
+            allowed_calls: Some(vec![AllowedCall {
+                target: token(),
+                selectors: vec!["0xa9059cbb".parse().unwrap()],
+            }]),
+            max_fee_per_gas: Some(U256::from(1_000_000_000u64)),
+            ..Default::default()
+        });
+
+        let approve = Execution::new(token(), U256::zero(), "0x095ea7b30000".parse().unwrap());
+        let other = Execution::new(Address::from_low_u64_be(0x30), U256::one(), Bytes::default());
+        let rejected = engine.approve(&uo(&[transfer(), approve, other]), NOW).unwrap_err();
+        assert_eq!(
+            rejected.0,
+            vec![
+                SponsorshipRejection::MaxFeeTooHigh(U256::from(2_000_000_000u64), U256::from(1_000_000_000u64)),
+                SponsorshipRejection::SelectorNotAllowed(token(), "0x095ea7b3".parse().unwrap()),
+                SponsorshipRejection::TargetNotAllowed(Address::from_low_u64_be(0x30)),
+            ]
+        );
+
+        let cheap = uo(&[transfer()]).max_fee_per_gas(U256::from(1_000_000_000u64));
+        assert!(engine.approve(&cheap, NOW).is_ok());
+
+        let garbage = cheap.call_data("0xdeadbeef".parse().unwrap());
+        assert!(matches!(
+            engine.pre_check(&garbage, NOW).unwrap_err().0[..],
+            [SponsorshipRejection::UndecodableCallData(_)]
+        ));
+    }
+
+    #[test]
+    fn sponsors_deployments_only_through_allowed_factories() {
+        let factory = Address::from_low_u64_be(0x40);
+        let engine = SponsorshipEngine::new(SponsorshipPolicy {
+            allowed_factories: Some(vec![factory]),
+            ..Default::default()
+        });
+
+        assert!(engine.approve(&uo(&[transfer()]), NOW).is_ok());
+        assert!(engine.approve(&uo(&[transfer()]).factory(factory), NOW).is_ok());
+
+        let other = Address::from_low_u64_be(0x41);
+        assert_eq!(
+            engine.pre_check(&uo(&[transfer()]).factory(other), NOW).unwrap_err().0,
+            vec![SponsorshipRejection::FactoryNotAllowed(other)]
+        );
+    }
+
+    #[test]
+    fn charges_daily_gas_budget_per_sender() {
+        let engine = SponsorshipEngine::new(SponsorshipPolicy {
+            daily_gas_budget: Some(U256::from(250_000u64)),
+            ..Default::default()
+        });
+        let uo = uo(&[transfer()]);
+        assert_eq!(operation_gas(&uo), U256::from(100_000u64));
+
+        assert_eq!(engine.approve(&uo, NOW).unwrap().gas, U256::from(100_000u64));
+        engine.approve(&uo, NOW).unwrap();
+        assert_eq!(engine.remaining_budget(uo.sender, NOW), Some(U256::from(50_000u64)));
+        assert_eq!(
+            engine.approve(&uo, NOW).unwrap_err().0,
+            vec![SponsorshipRejection::DailyGasBudgetExceeded(uo.sender, U256::from(100_000u64), U256::from(50_000u64))]
+        );
+        assert!(engine.pre_check(&uo, NOW).is_ok());
+
+        // The budget starts over the next day.
+        assert!(engine.approve(&uo, NOW + SECONDS_PER_DAY).is_ok());
+    }
+
+    #[test]
+    fn rejects_gas_limits_above_uint128_without_overflowing() {
+        let engine = SponsorshipEngine::new(SponsorshipPolicy {
+            daily_gas_budget: Some(U256::from(250_000u64)),
+            ..Default::default()
+        });
+        let overflowing = uo(&[transfer()])
+            .call_gas_limit(U256::MAX)
+            .verification_gas_limit(U256::MAX);
+        assert_eq!(operation_gas(&overflowing), U256::MAX);
+        assert_eq!(
+            engine.approve(&overflowing, NOW).unwrap_err().0,
+            vec![
+                SponsorshipRejection::GasLimitTooHigh("callGasLimit".to_string(), U256::MAX),
+                SponsorshipRejection::GasLimitTooHigh("verificationGasLimit".to_string(), U256::MAX),
+            ]
+        );
+        assert!(engine.pre_check(&overflowing, NOW).is_err());
+        assert_eq!(engine.remaining_budget(overflowing.sender, NOW), Some(U256::from(250_000u64)));
+
+        let largest = uo(&[transfer()]).call_gas_limit(U256::from(u128::MAX));
+        assert!(engine.pre_check(&largest, NOW).is_ok());
+    }
+
+    #[test]
+    fn sponsors_only_inside_time_windows() {
+        let engine = SponsorshipEngine::new(SponsorshipPolicy {
+            time_windows: vec![TimeWindow { start: NOW, end: NOW + 60 }],
+            ..Default::default()
+        });
+        let uo = uo(&[transfer()]);
+        assert_eq!(engine.approve(&uo, NOW + 10).unwrap().valid_until, NOW + 60);
+        assert_eq!(engine.pre_check(&uo, NOW + 60).unwrap_err().0, vec![SponsorshipRejection::OutsideTimeWindow]);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::primitives::user_operation::UserOperationPartial;
+use crate::verifying_paymaster::DUMMY_SIGNATURE;
+use ethers::types::{Address, Bytes, U256};
+use serde::{Deserialize, Serialize};
+
+/// `paymasterData` mode of the singleton paymaster in which the account pays in an ERC-20 token.
+pub const ERC20_MODE: u8 = 1;
+
+/// Gas quoted for the paymaster's validation until the bundler or service gives its own.
+pub const TOKEN_PAYMASTER_VERIFICATION_GAS_LIMIT: u64 = 100_000;
+
+/// Price of gas in `token` at `paymaster`, as returned by `pimlico_getTokenQuotes`.
+///
+/// The paymaster pulls `(actualGasCost + postOpGas * gasPrice) * exchangeRate / 1e18` tokens
+/// from the account in `postOp`, so an `approve` in the operation's own batch is enough.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenQuote {
+    pub paymaster: Address,
+    pub token: Address,
+    /// Gas of the `postOp` transfer, charged on top of the operation's own gas.
+    pub post_op_gas: U256,
+    /// Token units per 1e18 wei.
+    pub exchange_rate: U256,
+}
+
+impl TokenQuote {
+    /// Most tokens the paymaster can charge for `user_operation`: its prefund at
+    /// `maxFeePerGas` plus `postOpGas`, converted at the exchange rate. Unset limits count as 0.
+    /// Fails when the amount does not fit in a uint256.
+    pub fn max_cost(&self, user_operation: &UserOperationPartial) -> anyhow::Result<U256> {
+        let overflow = || anyhow::anyhow!("token paymaster cost of the user operation overflows uint256");
+        let gas = [
+            user_operation.call_gas_limit,
+            user_operation.verification_gas_limit,
+            user_operation.pre_verification_gas,
+            user_operation.paymaster_verification_gas_limit,
+            user_operation.paymaster_post_op_gas_limit,
+        ]
+        .into_iter()
+        .flatten()
+        .try_fold(self.post_op_gas, |total, limit| total.checked_add(limit))
+        .ok_or_else(overflow)?;
+        let max_fee_per_gas = user_operation.max_fee_per_gas.unwrap_or_default();
+        let cost = gas
+            .checked_mul(max_fee_per_gas)
+            .and_then(|wei| wei.checked_mul(self.exchange_rate))
+            .ok_or_else(overflow)?;
+        Ok(cost / U256::exp10(18))
+    }
+}
+
+/// `paymasterData` of the singleton paymaster in ERC-20 mode, without the optional prefund,
+/// constant fee and recipient. Everything is packed:
+/// `mode ‖ flags ‖ uint48 validUntil ‖ uint48 validAfter ‖ token ‖ uint128 postOpGas ‖
+/// uint256 exchangeRate ‖ uint128 paymasterValidationGasLimit ‖ treasury ‖ signature`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenPaymasterData {
+    pub allow_all_bundlers: bool,
+    pub valid_until: u64,
+    pub valid_after: u64,
+    pub token: Address,
+    pub post_op_gas: U256,
+    pub exchange_rate: U256,
+    pub paymaster_validation_gas_limit: U256,
+    pub treasury: Address,
+    pub signature: Bytes,
+}
+
+impl TokenPaymasterData {
+    /// Data for estimation at `quote`. The treasury and signature are only known to the
+    /// paymaster service, which replaces them in `pm_getPaymasterData`.
+    pub fn stub(quote: &TokenQuote, paymaster_validation_gas_limit: U256) -> Self {
+        Self {
+            allow_all_bundlers: true,
+            valid_until: 0,
+            valid_after: 0,
+            token: quote.token,
+            post_op_gas: quote.post_op_gas,
+            exchange_rate: quote.exchange_rate,
+            paymaster_validation_gas_limit,
+            treasury: quote.paymaster,
+            signature: DUMMY_SIGNATURE.to_vec().into(),
+        }
+    }
+
+    pub fn encode(&self) -> anyhow::Result<Bytes> {
+        let mut data = Vec::with_capacity(118 + self.signature.len());
+        data.push((ERC20_MODE << 1) | u8::from(self.allow_all_bundlers));
+        // No prefund, constant fee or recipient follow.
+        data.push(0);
+        data.extend_from_slice(&uint48(self.valid_until, "validUntil")?);
+        data.extend_from_slice(&uint48(self.valid_after, "validAfter")?);
+        data.extend_from_slice(self.token.as_bytes());
+        data.extend_from_slice(&uint128(self.post_op_gas, "postOpGas")?);
+        let mut exchange_rate = [0u8; 32];
+        self.exchange_rate.to_big_endian(&mut exchange_rate);
+        data.extend_from_slice(&exchange_rate);
+        data.extend_from_slice(&uint128(self.paymaster_validation_gas_limit, "paymasterValidationGasLimit")?);
+        data.extend_from_slice(self.treasury.as_bytes());
+        data.extend_from_slice(&self.signature);
+        Ok(data.into())
+    }
+}
+
+fn uint48(value: u64, field: &str) -> anyhow::Result<[u8; 6]> {
+    if value >> 48 != 0 {
+        return Err(anyhow::anyhow!("{} does not fit in uint48", field));
+    }
+    let mut packed = [0u8; 6];
+    packed.copy_from_slice(&value.to_be_bytes()[2..]);
+    Ok(packed)
+}
+
+fn uint128(value: U256, field: &str) -> anyhow::Result<[u8; 16]> {
+    if value.bits() > 128 {
+        return Err(anyhow::anyhow!("{} does not fit in uint128", field));
+    }
+    let mut word = [0u8; 32];
+    value.to_big_endian(&mut word);
+    let mut packed = [0u8; 16];
+    packed.copy_from_slice(&word[16..]);
+    Ok(packed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn quote() -> TokenQuote {
+        serde_json::from_value(json!({
+            "paymaster": "0x0000000000000039cd5e8aE05257CE51C473ddd1",
+            "token": "0x1c7D4B196Cb0C7B01d743Fbc6116a902379C7238",
+            "postOpGas": "0xc350",
+            "exchangeRate": "0x8ac7230489e80000",
+            "exchangeRateNativeToUsd": "0x5f5e100",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn max_cost_covers_all_gas_and_post_op_at_max_fee() {
+        let user_operation = UserOperationPartial {
+            call_gas_limit: Some(U256::from(100_000u64)),
+            verification_gas_limit: Some(U256::from(200_000u64)),
+            pre_verification_gas: Some(U256::from(50_000u64)),
+            paymaster_verification_gas_limit: Some(U256::from(100_000u64)),
+            paymaster_post_op_gas_limit: Some(U256::from(50_000u64)),
+            max_fee_per_gas: Some(U256::from(2_000_000_000u64)),
+            ..Default::default()
+        };
+        // 550_000 gas at 2 gwei is 0.0011 ETH, at 10 tokens per ETH.
+        assert_eq!(quote().max_cost(&user_operation).unwrap(), U256::from(11_000_000_000_000_000u64));
+    }
+
+    #[test]
+    fn max_cost_fails_instead_of_overflowing() {
+        let mut user_operation = UserOperationPartial {
+            call_gas_limit: Some(U256::MAX),
+            verification_gas_limit: Some(U256::one()),
+            max_fee_per_gas: Some(U256::one()),
+            ..Default::default()
+        };
+        assert!(quote().max_cost(&user_operation).is_err());
+
+        user_operation.call_gas_limit = Some(U256::from(u128::MAX));
+        user_operation.max_fee_per_gas = Some(U256::from(u128::MAX));
+        assert!(quote().max_cost(&user_operation).is_err());
+
+        let quote = TokenQuote {
+            exchange_rate: U256::MAX,
+            ..quote()
+        };
+        user_operation.call_gas_limit = Some(U256::from(100_000u64));
+        user_operation.max_fee_per_gas = Some(U256::from(1_000_000_000u64));
+        assert!(quote.max_cost(&user_operation).is_err());
+    }
+
+    #[test]
+    fn encodes_erc20_mode_layout() {
+        let quote = quote();
+        let mut data = TokenPaymasterData::stub(&quote, U256::from(100_000u64));
+        data.valid_until = 1_700_000_000;
+        let encoded = data.encode().unwrap();
+
+        assert_eq!(encoded.len(), 1 + 117 + 65);
+        assert_eq!(encoded[0], 0x03);
+        assert_eq!(encoded[1], 0x00);
+        assert_eq!(U256::from_big_endian(&encoded[2..8]), U256::from(1_700_000_000u64));
+        assert!(encoded[8..14].iter().all(|byte| *byte == 0));
+        assert_eq!(&encoded[14..34], quote.token.as_bytes());
+        assert_eq!(U256::from_big_endian(&encoded[34..50]), quote.post_op_gas);
+        assert_eq!(U256::from_big_endian(&encoded[50..82]), quote.exchange_rate);
+        assert_eq!(U256::from_big_endian(&encoded[82..98]), U256::from(100_000u64));
+        assert_eq!(&encoded[98..118], quote.paymaster.as_bytes());
+        assert_eq!(&encoded[118..], &DUMMY_SIGNATURE[..]);
+
+        data.post_op_gas = U256::one() << 128;
+        assert!(data.encode().is_err());
+    }
+}
@@ -8,6 +8,7 @@ use ethers::{
     providers::Middleware,
     types::{Address, Bytes, H160, U256, H256},
 };
+use crate::primitives::execution::Execution;
 use std::sync::Arc;
 use std::fmt::Debug;
 
@@ -23,6 +24,7 @@ pub trait SmartWalletAccountFactory<M: Middleware>: Debug {
 
     fn clone_box(&self) -> Box<dyn SmartWalletAccountFactory<M>>;
 }
+
 pub trait MSABasicFactory<M: Middleware>: Debug {
     fn create_account(&self, salt: H256, init_code: Bytes)
         -> FunctionCall<Arc<M>, M, H160>;
@@ -33,6 +35,8 @@ pub trait MSABasicFactory<M: Middleware>: Debug {
         init_code: Bytes,
     ) -> FunctionCall<Arc<M>, M, H160>;
 
+    fn implementation(&self) -> FunctionCall<Arc<M>, M, H160>;
+
     fn clone_box(&self) -> Box<dyn MSABasicFactory<M>>;
 }
 
@@ -47,5 +51,9 @@ pub trait SmartWalletAccount: Debug + Send {
         call.abi_encode()
     }
 
+    /// Call data of the account's own execute function running all `executions` in order,
+    /// atomically when there is more than one.
+    fn calldata_gen_executions(&self, executions: &[Execution]) -> anyhow::Result<Bytes>;
+
     fn clone_box(&self) -> Box<dyn SmartWalletAccount>;
 }
@@ -1,7 +1,5 @@
 use crate::traits::{SmartWalletAccount, SmartWalletAccountFactory, MSABasicFactory};
 use crate::consts::{GETH_SIMPLE_ACCOUNT_FACTORY, SIMPLE_ACCOUNT_FACTORY, MSA_FACTORY_SEPOLIA};
-use ethers::middleware::transformer::ds_proxy::factory;
-use ethers::signers::Wallet;
 use ethers::{
     prelude::{NonceManagerMiddleware, SignerMiddleware},
     signers::LocalWallet,
@@ -14,6 +12,7 @@ use parking_lot::Mutex;
 use hashbrown::HashMap;
 
 use std::fmt::Debug;
+use std::str::FromStr;
 
 // TODO: Figure out how to realize with alloy.rs.
 // Seems this type is only used in bundler implementation. Hopefully we don't have to think this when we just want to have userOperation and send it to external bundler.
@@ -36,7 +35,9 @@ pub struct EstimateResult {
     pub pre_verification_gas: U256,
     pub verification_gas_limit: U256,
     pub call_gas_limit: U256,
+    #[serde(default)]
     pub paymaster_verification_gas_limit: U256,
+    #[serde(default)]
     pub paymaster_post_op_gas_limit: U256,
 }
 
@@ -47,19 +48,6 @@ pub struct Response<R> {
     pub result: R,
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct ErrorResponse {
-    pub(crate) jsonrpc: String,
-    pub(crate) id: u64,
-    pub(crate) error: JsonRpcError,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct JsonRpcError {
-    pub code: i64,
-    pub message: String,
-}
-
 pub struct DeployedContract<C> {
     contract: C,
     pub address: Address,
@@ -83,14 +71,16 @@ pub enum WalletRegistry {
     MSABasicAccount,
 }
 
-#[allow(dead_code)]
-impl WalletRegistry {
-    pub fn from_str(s: &str) -> anyhow::Result<WalletRegistry> {
+impl FromStr for WalletRegistry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<WalletRegistry> {
 
         match s {
             "simple-account" => Ok(WalletRegistry::SimpleAccount),
             "simple-account-test" => Ok(WalletRegistry::SimpleAccount),
             "msa-basic-account" => Ok(WalletRegistry::MSABasicAccount),
+            "msa-account-sepolia" => Ok(WalletRegistry::MSABasicAccount),
             _ => Err(anyhow::anyhow!("{} wallet currently not supported", s)),
         }
 
@@ -103,19 +93,20 @@ pub enum WalletFactoryAddresses {
 }
 
 
-#[allow(dead_code)]
-impl WalletFactoryAddresses {
-    pub fn from_str(s: &str) -> anyhow::Result<WalletFactoryAddresses> {
+impl FromStr for WalletFactoryAddresses {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<WalletFactoryAddresses> {
 
         match s {
-            "simple-account" => Ok(WalletFactoryAddresses::SimpleAccountFactoryAddress((
-                SIMPLE_ACCOUNT_FACTORY.parse::<Address>().unwrap()),
+            "simple-account" => Ok(WalletFactoryAddresses::SimpleAccountFactoryAddress(
+                SIMPLE_ACCOUNT_FACTORY.parse::<Address>().unwrap(),
             )),
-            "simple-account-test" => Ok(WalletFactoryAddresses::SimpleAccountFactoryAddress((
-                GETH_SIMPLE_ACCOUNT_FACTORY.parse::<Address>().unwrap()),
+            "simple-account-test" => Ok(WalletFactoryAddresses::SimpleAccountFactoryAddress(
+                GETH_SIMPLE_ACCOUNT_FACTORY.parse::<Address>().unwrap(),
             )),
-            "msa-account-sepolia" => Ok(WalletFactoryAddresses::MSABasicFactoryAddress((
-                MSA_FACTORY_SEPOLIA.parse::<Address>().unwrap()),
+            "msa-account-sepolia" => Ok(WalletFactoryAddresses::MSABasicFactoryAddress(
+                MSA_FACTORY_SEPOLIA.parse::<Address>().unwrap(),
             )),
             _ => Err(anyhow::anyhow!("{}'s factory not supported", s)),
         }
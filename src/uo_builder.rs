@@ -1,9 +1,15 @@
+use crate::erc20;
 use crate::errors::UserOpBuilderError;
 use crate::gen::{SimpleAccount, MSABasic, SimpleAccountFactory, MSAFactory};
-use crate::traits::{SmartWalletAccount, SmartWalletAccountFactory, MSABasicFactory};
+use crate::paymaster::PaymasterClient;
+use crate::token_paymaster::{TokenPaymasterData, TokenQuote, TOKEN_PAYMASTER_VERIFICATION_GAS_LIMIT};
+use crate::verifying_paymaster::DUMMY_SIGNATURE;
+use crate::traits::SmartWalletAccount;
 
 use crate::types::{WalletRegistry, WalletFactoryRegistry, WalletFactoryAddresses};
 
+use crate::primitives::account_address::AccountAddressDeriver;
+use crate::primitives::execution::Execution;
 use crate::primitives::user_operation::{UserOperation, UserOperationHash, UserOperationPartial};
 
 use ethers::{
@@ -11,6 +17,7 @@ use ethers::{
     types::{Address, Bytes, U256, H256},
     utils::keccak256,
 };
+use std::str::FromStr;
 use std::sync::Arc;
 use anyhow::Ok;
 
@@ -23,8 +30,12 @@ pub struct UserOperationBuilder<M: Middleware + 'static> {
     scw_address: Option<Address>,
     signer_address: Address,
     salt: Option<u64>,
+    init_code: Bytes,
+    address_deriver: Option<AccountAddressDeriver>,
     uo: UserOperationPartial,
     uo_hash: Option<UserOperationHash>,
+    executions: Vec<Execution>,
+    token_paymaster: Option<TokenQuote>,
 }
 
 impl<M: Middleware> Clone for UserOperationBuilder<M> {
@@ -37,8 +48,12 @@ impl<M: Middleware> Clone for UserOperationBuilder<M> {
             scw_address: self.scw_address,
             signer_address: self.signer_address,
             salt: self.salt,
+            init_code: self.init_code.clone(),
+            address_deriver: self.address_deriver,
             uo: self.uo.clone(),
             uo_hash: self.uo_hash,
+            executions: self.executions.clone(),
+            token_paymaster: self.token_paymaster.clone(),
         }
     }
 }
@@ -81,8 +96,12 @@ impl<M: Middleware + 'static> UserOperationBuilder<M> {
             scw_address,
             signer_address: eoa_wallet_address,
             salt,
+            init_code: Bytes::new(),
+            address_deriver: None,
             uo,
             uo_hash: None,
+            executions: Vec::new(),
+            token_paymaster: None,
         })
     }
 
@@ -152,6 +171,10 @@ impl<M: Middleware + 'static> UserOperationBuilder<M> {
         self.salt
     }
 
+    pub fn init_code(&self) -> &Bytes {
+        &self.init_code
+    }
+
     pub fn uo(&self) -> &UserOperationPartial {
         &self.uo
     }
@@ -160,25 +183,56 @@ impl<M: Middleware + 'static> UserOperationBuilder<M> {
         &self.uo_hash
     }
 
+    pub fn executions(&self) -> &[Execution] {
+        &self.executions
+    }
+
+    pub fn token_paymaster(&self) -> Option<&TokenQuote> {
+        self.token_paymaster.as_ref()
+    }
+
     pub async fn set_scw_address(&mut self) -> anyhow::Result<Address> {
-        let scw_address;
-        match &self.factory_contract {
+        let scw_address = match &self.factory_contract {
             WalletFactoryRegistry::SimpleAccountFactory(factory) => {
                 let creator_address = self.factory_address;
                 let salt = U256::from(self.salt.expect("salt is none"));
-                scw_address = factory.generate_address(creator_address, salt).call().await?;
+                factory.generate_address(creator_address, salt).call().await?
             },
             WalletFactoryRegistry::MSABasicFactory(factory) => {
-                let hashed_salt = keccak256(self.salt.unwrap().to_be_bytes());
+                let hashed_salt = keccak256(self.salt.expect("salt is none").to_be_bytes());
                 let salt = H256::from(hashed_salt);
-                let init_code = Bytes::new();
-                scw_address = factory.get_address(salt, init_code).call().await?;
+                // Only the implementation is read from the factory, once; the address is derived locally.
+                let address_deriver = match self.address_deriver {
+                    Some(address_deriver) => address_deriver,
+                    None => {
+                        let implementation = factory.implementation().call().await?;
+                        let address_deriver = AccountAddressDeriver::new(self.factory_address, implementation);
+                        self.address_deriver = Some(address_deriver);
+                        address_deriver
+                    },
+                };
+                address_deriver.address(salt, &self.init_code)
             },
-        }
+        };
         self.scw_address = Some(scw_address);
         Ok(scw_address)
     }
 
+    /// Sets the `initCode` the MSA account is created with, e.g. [`AccountInit::init_code`],
+    /// which its counterfactual address commits to.
+    ///
+    /// [`AccountInit::init_code`]: crate::primitives::account_init::AccountInit::init_code
+    pub fn set_init_code(&mut self, init_code: Bytes) -> &mut Self {
+        self.init_code = init_code;
+        self
+    }
+
+    /// Sets `MSAFactory.implementation()` so the account address is derived without any call.
+    pub fn set_msa_implementation(&mut self, implementation: Address) -> &mut Self {
+        self.address_deriver = Some(AccountAddressDeriver::new(self.factory_address, implementation));
+        self
+    }
+
     pub fn set_uo(&mut self, uo: UserOperationPartial) -> &mut Self {
         self.uo = uo;
         self
@@ -190,6 +244,7 @@ impl<M: Middleware + 'static> UserOperationBuilder<M> {
         self.wallet_contract = wallet_contract;
         self.factory_contract = factory_contract;
         self.factory_address = factory_address;
+        self.address_deriver = None;
         Ok(self)
     }
 
@@ -243,7 +298,7 @@ impl<M: Middleware + 'static> UserOperationBuilder<M> {
         self
     }
 
-    pub fn set_uo_paymaster(&mut self, paymaster: String) -> &mut Self {
+    pub fn set_uo_paymaster(&mut self, paymaster: Address) -> &mut Self {
         self.uo.paymaster = Some(paymaster);
         self
     }
@@ -268,7 +323,91 @@ impl<M: Middleware + 'static> UserOperationBuilder<M> {
         self
     }
 
-    pub(crate) fn set_uo_hash(&mut self, uo_hash: UserOperationHash) -> &mut Self {
+    /// Sets the calls the account makes and `call_data` to their encoding for the wallet's
+    /// execute function, `execute`/`executeBatch` of a SimpleAccount or ERC-7579 `execute`.
+    /// In token paymaster mode they run in a batch after the paymaster's `approve`.
+    pub fn set_uo_executions(&mut self, executions: Vec<Execution>) -> anyhow::Result<&mut Self> {
+        self.executions = executions;
+        self.encode_executions()?;
+        Ok(self)
+    }
+
+    /// Pays gas in `quote.token` through its ERC-20 paymaster: sets the paymaster, its stub
+    /// data and gas limits, and prepends the `approve` covering [`TokenQuote::max_cost`].
+    pub fn set_token_paymaster(&mut self, quote: TokenQuote) -> anyhow::Result<&mut Self> {
+        self.uo.paymaster = Some(quote.paymaster);
+        self.uo.paymaster_verification_gas_limit = self
+            .uo
+            .paymaster_verification_gas_limit
+            .or(Some(U256::from(TOKEN_PAYMASTER_VERIFICATION_GAS_LIMIT)));
+        self.uo.paymaster_post_op_gas_limit = self.uo.paymaster_post_op_gas_limit.or(Some(quote.post_op_gas));
+        self.token_paymaster = Some(quote);
+        self.refresh_token_paymaster()
+    }
+
+    /// Asks the `paymaster` service for the price of `token` and switches to token paymaster mode.
+    pub async fn fetch_token_quote(
+        &mut self,
+        paymaster: &PaymasterClient,
+        token: Address,
+        entry_point: Address,
+    ) -> anyhow::Result<&mut Self> {
+        let chain_id = self.provider.get_chainid().await.map_err(UserOpBuilderError::<M>::MiddlewareError)?;
+        let quote = paymaster
+            .get_token_quotes(&[token], entry_point, chain_id.as_u64())
+            .await?
+            .into_iter()
+            .find(|quote| quote.token == token)
+            .ok_or(UserOpBuilderError::<M>::TokenNotQuoted(token))?;
+        self.set_token_paymaster(quote)
+    }
+
+    /// Recomputes the approved amount and the paymaster data. The amount covers the gas limits
+    /// and fees set now, so call it again whenever they change.
+    pub fn refresh_token_paymaster(&mut self) -> anyhow::Result<&mut Self> {
+        if let Some(quote) = &self.token_paymaster {
+            let validation_gas_limit = self.uo.paymaster_verification_gas_limit.unwrap_or_default();
+            self.uo.paymaster_data = Some(TokenPaymasterData::stub(quote, validation_gas_limit).encode()?);
+            self.encode_executions()?;
+        }
+        Ok(self)
+    }
+
+    /// Replaces the stub paymaster data with the data the `paymaster` service signs in
+    /// `pm_getPaymasterData`, with the quoted token in its context. The signature commits to the
+    /// gas limits and fees, so this is the last step before signing the user operation.
+    pub async fn fetch_token_paymaster_data(
+        &mut self,
+        paymaster: &PaymasterClient,
+        entry_point: Address,
+    ) -> anyhow::Result<&mut Self> {
+        let token = match &self.token_paymaster {
+            Some(quote) => quote.token,
+            None => return Err(anyhow::anyhow!("not in token paymaster mode")),
+        };
+        self.refresh_token_paymaster()?;
+        let chain_id = self.provider.get_chainid().await.map_err(UserOpBuilderError::<M>::MiddlewareError)?;
+        paymaster
+            .get_token_paymaster_data(&self.uo, entry_point, chain_id.as_u64(), token)
+            .await?
+            .fill(&mut self.uo);
+        Ok(self)
+    }
+
+    fn encode_executions(&mut self) -> anyhow::Result<()> {
+        let call_data = match &self.token_paymaster {
+            Some(quote) => {
+                let approve = erc20::approve(quote.token, quote.paymaster, quote.max_cost(&self.uo)?);
+                let batch = [&[approve][..], &self.executions].concat();
+                self.wallet_contract.calldata_gen_executions(&batch)?
+            },
+            None => self.wallet_contract.calldata_gen_executions(&self.executions)?,
+        };
+        self.uo.call_data = Some(call_data);
+        Ok(())
+    }
+
+    pub fn set_uo_hash(&mut self, uo_hash: UserOperationHash) -> &mut Self {
         self.uo_hash = Some(uo_hash);
         self
     }
@@ -287,18 +426,6 @@ impl<M: Middleware + 'static> UserOperationBuilder<M> {
             ));
         };
 
-        if self.uo.factory.is_none() {
-            return Err(anyhow::anyhow!(
-                UserOpBuilderError::<M>::MissingUserOperationField("factory".to_string())
-            ));
-        };
-
-        if self.uo.factory_data.is_none() {
-            return Err(anyhow::anyhow!(
-                UserOpBuilderError::<M>::MissingUserOperationField("factory_data".to_string())
-            ));
-        };
-
         if self.uo.call_data.is_none() {
             return Err(anyhow::anyhow!(
                 UserOpBuilderError::<M>::MissingUserOperationField("call_data".to_string())
@@ -335,38 +462,153 @@ impl<M: Middleware + 'static> UserOperationBuilder<M> {
             ));
         };
 
-        if self.uo.paymaster.is_none() {
-            return Err(anyhow::anyhow!(
-                UserOpBuilderError::<M>::MissingUserOperationField("paymaster".to_string())
-            ));
-        };
-
-        if self.uo.paymaster_verification_gas_limit.is_none() {
+        if self.uo.paymaster.is_some() && self.uo.paymaster_verification_gas_limit.is_none() {
             return Err(anyhow::anyhow!(
                 UserOpBuilderError::<M>::MissingUserOperationField("paymaster_verification_gas_limit".to_string())
             ));
         };
 
-        if self.uo.paymaster_post_op_gas_limit.is_none() {
+        if self.uo.paymaster.is_some() && self.uo.paymaster_post_op_gas_limit.is_none() {
             return Err(anyhow::anyhow!(
                 UserOpBuilderError::<M>::MissingUserOperationField("paymaster_post_op_gas_limit".to_string())
             ));
         };
 
-        if self.uo.paymaster_data.is_none() {
-            return Err(anyhow::anyhow!(
-                UserOpBuilderError::<M>::MissingUserOperationField("paymaster_data".to_string())
-            ));
-        };
-
         if self.uo.signature.is_none() {
             return Err(anyhow::anyhow!(
                 UserOpBuilderError::<M>::MissingUserOperationField("signature".to_string())
             ))
         };
 
+        let stub_paymaster_data = self.uo.paymaster_data.as_ref().is_some_and(|data| data.ends_with(&DUMMY_SIGNATURE));
+        if self.token_paymaster.is_some() && stub_paymaster_data {
+            return Err(anyhow::anyhow!(
+                UserOpBuilderError::<M>::TokenPaymasterDataNotSigned
+            ));
+        };
+
         let uo = UserOperation::from(self.uo.clone());
 
         Ok(uo)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gen::simple_account::simple_account::{ExecuteBatchCall, ExecuteCall};
+    use crate::primitives::execution::decode_execute;
+    use crate::primitives::mode_code::ModeCode;
+    use ethers::abi::AbiDecode;
+    use ethers::providers::{Http, Provider};
+
+    fn builder(wallet_name: &str) -> UserOperationBuilder<Provider<Http>> {
+        let provider = Arc::new(Provider::<Http>::try_from("http://localhost:8545").unwrap());
+        UserOperationBuilder::new(Address::zero(), wallet_name, None, provider, None).unwrap()
+    }
+
+    fn quote() -> TokenQuote {
+        TokenQuote {
+            paymaster: "0x0000000000000039cd5e8aE05257CE51C473ddd1".parse().unwrap(),
+            token: "0x1c7D4B196Cb0C7B01d743Fbc6116a902379C7238".parse().unwrap(),
+            post_op_gas: U256::from(50_000u64),
+            exchange_rate: U256::exp10(18),
+        }
+    }
+
+    #[test]
+    fn token_paymaster_mode_approves_before_the_calls() {
+        let quote = quote();
+        let call = Execution::new(quote.token, U256::zero(), "0xa9059cbb".parse().unwrap());
+
+        let mut builder = builder("msa-account-sepolia");
+        builder.set_uo_executions(vec![call.clone()]).unwrap();
+        let (mode, executions) = decode_execute(builder.uo().call_data.as_ref().unwrap()).unwrap();
+        assert_eq!((mode, executions), (ModeCode::single(), vec![call.clone()]));
+
+        builder.set_token_paymaster(quote.clone()).unwrap();
+        builder.set_uo_max_fee_per_gas(U256::from(1_000_000_000u64));
+        builder.refresh_token_paymaster().unwrap();
+        let uo = builder.uo();
+        assert_eq!(uo.paymaster, Some(quote.paymaster));
+        assert_eq!(uo.paymaster_post_op_gas_limit, Some(quote.post_op_gas));
+        assert_eq!(uo.paymaster_data.as_ref().unwrap().len(), 1 + 117 + 65);
+
+        let (mode, executions) = decode_execute(uo.call_data.as_ref().unwrap()).unwrap();
+        assert_eq!(mode, ModeCode::batch());
+        // Paymaster limits and postOpGas at 1 gwei, one token per ETH.
+        let max_cost = U256::from(200_000u64 * 1_000_000_000);
+        assert_eq!(executions, vec![erc20::approve(quote.token, quote.paymaster, max_cost), call]);
+    }
+
+    #[test]
+    fn simple_account_call_data_uses_its_own_execute() {
+        let quote = quote();
+        let call = Execution::new(quote.token, U256::zero(), "0xa9059cbb".parse().unwrap());
+
+        let mut builder = builder("simple-account");
+        builder.set_uo_executions(vec![call.clone()]).unwrap();
+        let execute = ExecuteCall::decode(builder.uo().call_data.as_ref().unwrap()).unwrap();
+        assert_eq!((execute.dest, execute.value, execute.func), (call.target, call.value, call.call_data.clone()));
+
+        builder.set_token_paymaster(quote.clone()).unwrap();
+        builder.set_uo_max_fee_per_gas(U256::from(1_000_000_000u64));
+        builder.refresh_token_paymaster().unwrap();
+        let batch = ExecuteBatchCall::decode(builder.uo().call_data.as_ref().unwrap()).unwrap();
+        assert_eq!(batch.dest, vec![quote.token, call.target]);
+        assert_eq!(batch.func[1], call.call_data);
+
+        let payment = Execution::new(quote.token, U256::one(), Bytes::default());
+        assert!(builder.set_uo_executions(vec![call, payment]).is_err());
+    }
+
+    /// Answers `eth_chainId` and `pm_getPaymasterData` like a node and a paymaster service.
+    async fn paymaster_service() -> String {
+        use axum::{routing::post, Json, Router};
+        use serde_json::{json, Value};
+
+        let app = Router::new().route(
+            "/",
+            post(|Json(request): Json<Value>| async move {
+                let result = match request["method"].as_str() {
+                    Some("eth_chainId") => json!("0xaa36a7"),
+                    Some("pm_getPaymasterData") => {
+                        assert_eq!(request["params"][3]["token"], json!(quote().token));
+                        json!({"paymaster": quote().paymaster, "paymasterData": format!("0x03{}", "11".repeat(65))})
+                    },
+                    method => panic!("unexpected method {:?}", method),
+                };
+                Json(json!({"jsonrpc": "2.0", "id": request["id"], "result": result}))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        url
+    }
+
+    #[tokio::test]
+    async fn token_paymaster_mode_fetches_the_signed_paymaster_data() {
+        let url = paymaster_service().await;
+        let provider = Arc::new(Provider::<Http>::try_from(url.as_str()).unwrap());
+        let mut builder = UserOperationBuilder::new(Address::zero(), "msa-account-sepolia", None, provider, None).unwrap();
+        builder
+            .set_uo_sender(Address::from_low_u64_be(1))
+            .set_uo_nonce(U256::zero())
+            .set_uo_call_gas_limit(U256::from(100_000u64))
+            .set_uo_verification_gas_limit(U256::from(100_000u64))
+            .set_uo_pre_verification_gas(U256::from(50_000u64))
+            .set_uo_max_fee_per_gas(U256::from(1_000_000_000u64))
+            .set_uo_max_priority_fee_per_gas(U256::from(1_000_000_000u64))
+            .set_uo_signature(Bytes::default());
+        builder.set_uo_executions(vec![Execution::new(quote().token, U256::zero(), Bytes::default())]).unwrap();
+        builder.set_token_paymaster(quote()).unwrap();
+        assert!(builder.build_uo().is_err());
+
+        let paymaster = PaymasterClient::new(url, serde_json::json!({"policy": "usdc"}));
+        builder.fetch_token_paymaster_data(&paymaster, Address::zero()).await.unwrap();
+        let uo = builder.build_uo().unwrap();
+        assert!(!uo.paymaster_data.ends_with(&DUMMY_SIGNATURE));
+        assert!(uo.paymaster_data.ends_with(&[0x11; 65]));
+    }
+}
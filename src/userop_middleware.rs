@@ -1,47 +1,51 @@
 use crate::{
-    errors::{UserOpMiddlewareError}, gen::SimpleAccount, traits::SmartWalletAccount, types::{ErrorResponse, EstimateResult, Request, Response, WalletMap}, uo_builder::UserOperationBuilder
+    contract_call,
+    erc20::{self, Erc20Operation, Erc20Permit},
+    fee_oracle::{self, BundlerFeeQuote, BundlerFeeSource, FeeStrategy, GasFees, FEE_HISTORY_BLOCKS},
+    nonce_manager::{split_nonce, NonceKey, NonceLanes, NonceManager},
+    bundler_client::BundlerClient,
+    errors::{BundlerClientError, UserOpMiddlewareError},
+    gas_retry::GasRetryPolicy,
+    paymaster::{PaymasterClient, PaymasterData, PaymasterStubData},
+    signing::{SigningStrategies, SigningStrategy},
+    verifying_paymaster::VerifyingPaymaster, gen::{Bootstrap, MSABasic, MSAFactory, SimpleAccount}, traits::SmartWalletAccount, types::{EstimateResult, WalletMap}, uo_builder::UserOperationBuilder
 };
 use async_trait::async_trait;
 use ethers::{
-    contract::abigen, providers::{Middleware, MiddlewareError}, signers::{LocalWallet as Wallet, Signer}, types::{transaction::eip2718::TypedTransaction, Address, Bytes, H256, U256}
+    contract::{abigen, parse_log}, providers::{Middleware, MiddlewareError}, signers::{LocalWallet as Wallet, Signer}, types::{transaction::eip2718::TypedTransaction, Address, BlockNumber, Bytes, H256, U256}
 };
 use hashbrown::HashMap;
 use parking_lot::Mutex;
 use rand::Rng;
-use regex::Regex;
-use serde_json::json;
-use crate::primitives::user_operation::{UserOperation, UserOperationHash, UserOperationPartial, UserOperationReceipt};
+use crate::primitives::account_address::AccountAddressDeriver;
+use crate::primitives::account_init::AccountInit;
+use crate::primitives::execution::{encode_batch, Execution};
+use crate::primitives::mode_code::{CallType, ModeCode};
+use crate::primitives::module::{self, AccountModules, ActiveFallbackHandler, ModuleType, SENTINEL};
+use crate::primitives::utils::decode_revert_reason;
+use crate::primitives::user_operation::{UserOperation, UserOperationByHash, UserOperationHash, UserOperationPartial, UserOperationReceipt};
 use std::fmt;
 use std::sync::Arc;
-use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::time::{Duration, Instant};
 
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct JsonRpcResponse<T> {
-    pub jsonrpc: String,
-    pub id: u64,
-    pub result: Option<T>,
-    pub error: Option<JsonRpcError>
-}
+pub use crate::bundler_client::{JsonRpcError, JsonRpcResponse};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct JsonRpcError {
-    pub code: i64,
-    pub message: String,
-}
+/// Entries read per `get*Paginated` call.
+const MODULE_PAGE_SIZE: u64 = 32;
+
+/// How long `wait_for_receipt` callers without their own limits wait, and how often they poll.
+pub const DEFAULT_RECEIPT_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_RECEIPT_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
 abigen!(EntryPoint, "src/abi/EntryPoint.json",);
-abigen!(
-    MSABasic, 
-    "./src/abi/MSABasic.json"
-);
 
 #[derive(Clone)]
 pub struct UserOpMiddleware<M> {
     pub inner: M,
     pub entry_point_address: Address,
     pub rpc_address: String,
+    pub bundler: BundlerClient,
     pub chain_id: u64,
     #[doc(hidden)]
     pub wallet: Wallet,
@@ -50,6 +54,20 @@ pub struct UserOpMiddleware<M> {
     pub validator: Address,
     pub factory: Address,
     pub bootstrap: Address,
+    /// Salt and modules `sender` is deployed with by `factory` if it has no code yet.
+    pub account_salt: H256,
+    pub account_init: AccountInit,
+    pub nonce_manager: Arc<NonceManager>,
+    pub nonce_lanes: Arc<NonceLanes>,
+    pub fee_strategy: FeeStrategy,
+    pub bundler_fee_source: Option<Arc<dyn BundlerFeeSource>>,
+    pub gas_retry: GasRetryPolicy,
+    pub paymaster: Option<PaymasterClient>,
+    pub verifying_paymaster: Option<Arc<VerifyingPaymaster>>,
+    pub signing: SigningStrategies,
+    /// Bearer token the JSON-RPC signing methods require; they are disabled without one.
+    #[doc(hidden)]
+    pub rpc_signing_token: Option<String>,
 }
 
 impl<M: Middleware + 'static + fmt::Debug + Clone> fmt::Debug for UserOpMiddleware<M> {
@@ -88,6 +106,7 @@ impl<M: Middleware + 'static + fmt::Debug + Clone> Middleware for UserOpMiddlewa
     }
 }
 impl<M: Middleware + 'static + fmt::Debug + Clone> UserOpMiddleware<M> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         inner: M,
         entry_point_address: Address,
@@ -99,6 +118,7 @@ impl<M: Middleware + 'static + fmt::Debug + Clone> UserOpMiddleware<M> {
         bootstrap: Address,
     ) -> Self {
         let chain_id = wallet.chain_id();
+        let rpc_address = rpc_address.into();
 
         let wallet_account = Box::new(SimpleAccount::new(Address::default(), inner.clone().into()));
         let wallet_contract: Box<dyn SmartWalletAccount> = wallet_account;
@@ -108,17 +128,104 @@ impl<M: Middleware + 'static + fmt::Debug + Clone> UserOpMiddleware<M> {
         Self {
             inner,
             entry_point_address,
-            rpc_address: rpc_address.into(),
+            bundler: BundlerClient::new(&rpc_address),
+            rpc_address,
             chain_id,
             wallet,
             wallet_map,
             sender,
             validator,
             factory,
-            bootstrap
+            bootstrap,
+            account_salt: H256::zero(),
+            account_init: AccountInit::with_validator(validator),
+            nonce_manager: Arc::new(NonceManager::new()),
+            nonce_lanes: Arc::new(NonceLanes::new(NonceKey::from_validator(validator), 1).expect("one lane")),
+            fee_strategy: FeeStrategy::default(),
+            bundler_fee_source: None,
+            gas_retry: GasRetryPolicy::default(),
+            paymaster: None,
+            verifying_paymaster: None,
+            signing: SigningStrategies::default(),
+            rpc_signing_token: None,
+        }
+    }
+
+    pub fn with_fee_strategy(mut self, fee_strategy: FeeStrategy) -> Self {
+        self.fee_strategy = fee_strategy;
+        self
+    }
+
+    /// Takes fees from the bundler's own gas price method, e.g. [`PimlicoFeeSource`]; `None`
+    /// estimates them from the node for generic bundlers.
+    ///
+    /// [`PimlicoFeeSource`]: crate::fee_oracle::PimlicoFeeSource
+    pub fn with_bundler_fee_source(mut self, fee_source: Option<Arc<dyn BundlerFeeSource>>) -> Self {
+        self.bundler_fee_source = fee_source;
+        self
+    }
+
+    /// Sets how [`sign_and_send`](Self::sign_and_send) handles gas limits the bundler rejects.
+    pub fn with_gas_retry(mut self, gas_retry: GasRetryPolicy) -> Self {
+        self.gas_retry = gas_retry;
+        self
+    }
+
+    /// Sponsors the user operations built by the middleware through an ERC-7677 paymaster
+    /// service.
+    pub fn with_paymaster(mut self, paymaster: Option<PaymasterClient>) -> Self {
+        self.paymaster = paymaster;
+        self
+    }
+
+    /// Serves `pm_getPaymasterStubData` and `pm_getPaymasterData` by signing for our own
+    /// verifying paymaster.
+    pub fn with_verifying_paymaster(mut self, verifying_paymaster: Option<VerifyingPaymaster>) -> Self {
+        self.verifying_paymaster = verifying_paymaster.map(Arc::new);
+        self
+    }
+
+    /// Sets how user operations are signed for each validator module; EIP-191 by default.
+    pub fn with_signing_strategies(mut self, signing: SigningStrategies) -> Self {
+        self.signing = signing;
+        self
+    }
+
+    /// Enables `uo_signUserOperation` and `uo_signAndSendUserOperation` for requests carrying
+    /// `token` as their bearer token.
+    pub fn with_rpc_signing_token(mut self, token: Option<String>) -> Self {
+        self.rpc_signing_token = token.filter(|token| !token.is_empty());
+        self
+    }
+
+    /// Whether `bearer` is the token that enables signing over JSON-RPC. Always false when
+    /// no token is configured.
+    pub fn authorizes_rpc_signing(&self, bearer: Option<&str>) -> bool {
+        match (&self.rpc_signing_token, bearer) {
+            (Some(token), Some(bearer)) => {
+                // Compares every byte so the time taken does not tell how much of it matched.
+                token.len() == bearer.len()
+                    && token.bytes().zip(bearer.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+            },
+            _ => false,
         }
     }
 
+    /// Spreads the user operations of `sender` over `lanes` nonce keys of the validator, so up to
+    /// `lanes` of them can be pending at once without waiting for each other.
+    pub fn with_nonce_lanes(mut self, lanes: u32) -> anyhow::Result<Self> {
+        self.nonce_lanes = Arc::new(NonceLanes::new(self.nonce_key(), lanes)?);
+        Ok(self)
+    }
+
+    /// Sets how `sender` is deployed on its first user operation. By default it is created with
+    /// salt zero and only `validator` installed.
+    pub fn with_account_init(mut self, salt: H256, account_init: AccountInit) -> Self {
+        self.account_salt = salt;
+        self.account_init = account_init;
+        self
+    }
+
     #[allow(dead_code)]
     fn entry_point_address(&self) -> &Address {
         &self.entry_point_address
@@ -142,82 +249,406 @@ impl<M: Middleware + 'static + fmt::Debug + Clone> UserOpMiddleware<M> {
     pub async fn estimate_user_operation_gas(
         &self,
         user_operation: &UserOperationPartial
-    ) -> anyhow::Result<Response<EstimateResult>> {
-        let params = vec![json!(user_operation), json!(self.entry_point_address)];
-        let req_body = Request {
-            jsonrpc: "2.0".to_string(),
-            method: "eth_estimateUserOperationGas".to_string(),
-            params: params.clone(),
-            id: 1,
-        };
-
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&self.rpc_address)
-            .json(&req_body)
-            .send()
-            .await?;
-
-        Self::handle_response(response).await
+    ) -> anyhow::Result<EstimateResult> {
+        self.bundler
+            .estimate_user_operation_gas(user_operation, self.entry_point_address)
+            .await
+            .map_err(Self::bundler_error)
     }
 
     pub async fn send_user_operation(
         &self,
         user_operation: &UserOperationPartial
-    ) -> anyhow::Result<Response<H256>> {
-        let req_body = Request {
-            jsonrpc: "2.0".to_string(),
-            method: "eth_sendUserOperation".to_string(),
-            params: vec![json!(user_operation), json!(self.entry_point_address)],
-            id: 1,
+    ) -> anyhow::Result<UserOperationHash> {
+        let sent = self.send_once(user_operation).await;
+        if let Err(err) = &sent {
+            self.handle_rejected(user_operation, err).await;
+        }
+        sent
+    }
+
+    /// Sends `user_operation` and, once the bundler accepts it, moves the nonce sequence past it.
+    async fn send_once(
+        &self,
+        user_operation: &UserOperationPartial
+    ) -> anyhow::Result<UserOperationHash> {
+        let sent = self.bundler
+            .send_user_operation(user_operation, self.entry_point_address)
+            .await
+            .map_err(Self::bundler_error);
+
+        if let (Ok(_), Some(sender), Some(nonce)) = (&sent, user_operation.sender, user_operation.nonce) {
+            self.nonce_manager.confirm(sender, nonce);
+        }
+        sent
+    }
+
+    /// Gives the nonce of an operation the bundler rejected back, or resyncs its sequence when
+    /// the nonce itself was rejected. Any other failure, e.g. a lost connection, may have
+    /// reached the mempool, so the nonce stays reserved.
+    async fn handle_rejected(&self, user_operation: &UserOperationPartial, err: &anyhow::Error) {
+        let (Some(sender), Some(nonce)) = (user_operation.sender, user_operation.nonce) else {
+            return;
+        };
+        let Some(error) = err.downcast_ref::<UserOpMiddlewareError<M>>().filter(|error| error.rejection().is_some()) else {
+            return;
         };
+        if !error.is_nonce_error() {
+            self.nonce_manager.release(sender, nonce);
+            return;
+        }
+        let (key, _) = split_nonce(nonce);
+        if let Err(err) = self.resync_nonce(sender, key).await {
+            log::warn!("failed to resync nonce of {:?}: {:#}", sender, err);
+        }
+    }
 
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&self.rpc_address)
-            .json(&req_body)
-            .send()
-            .await?;
+    /// Signs `user_operation` with the wallet and sends it. When the bundler rejects a gas limit
+    /// as too low, the limit is raised as the gas retry policy says and the operation is signed
+    /// and sent again with the same nonce, at most `max_retries` times.
+    pub async fn sign_and_send(
+        &self,
+        mut user_operation: UserOperationPartial,
+    ) -> anyhow::Result<UserOperationHash> {
+        let mut retries = 0;
+        loop {
+            let signed = UserOperationPartial::from(self.sign_uo(UserOperation::from(user_operation.clone())).await?);
+            let err = match self.send_once(&signed).await {
+                Ok(user_operation_hash) => return Ok(user_operation_hash),
+                Err(err) => err,
+            };
+
+            let bumped = retries < self.gas_retry.max_retries
+                && err
+                    .downcast_ref::<UserOpMiddlewareError<M>>()
+                    .is_some_and(|error| self.gas_retry.bump(&mut user_operation, error));
+            // Only a retry reuses the nonce; otherwise nothing is sent with it.
+            if !bumped {
+                self.handle_rejected(&signed, &err).await;
+                return Err(err);
+            }
+            // The paymaster signature commits to the gas limits that were just raised.
+            if let (Some(paymaster), Some(_)) = (&self.paymaster, user_operation.paymaster) {
+                let paymaster_data = paymaster
+                    .get_paymaster_data(&user_operation, self.entry_point_address, self.chain_id)
+                    .await;
+                match paymaster_data {
+                    Ok(paymaster_data) => paymaster_data.fill(&mut user_operation),
+                    Err(paymaster_err) => {
+                        self.handle_rejected(&signed, &err).await;
+                        return Err(paymaster_err.into());
+                    },
+                }
+            }
+            retries += 1;
+            log::info!("resending user operation with raised gas limits ({}/{}): {:#}", retries, self.gas_retry.max_retries, err);
+        }
+    }
 
-        Self::handle_response(response).await
+    /// Nonce key selecting the configured validator.
+    pub fn nonce_key(&self) -> NonceKey {
+        NonceKey::from_validator(self.validator)
     }
 
     pub async fn get_nonce(
         &self,
     ) -> anyhow::Result<U256> {
+        self.get_nonce_of(self.sender).await
+    }
 
-        let mut padded_bytes = [0u8; 32];
-        padded_bytes[8..28].copy_from_slice(self.validator.as_bytes());
-        let validator_for_input = U256::from_big_endian(&padded_bytes);
+    pub async fn get_nonce_of(
+        &self,
+        sender: Address,
+    ) -> anyhow::Result<U256> {
+        self.get_nonce_with_key(sender, self.nonce_key()).await
+    }
 
+    /// `EntryPoint.getNonce(sender, key)`, the next nonce of the `key` sequence on chain.
+    pub async fn get_nonce_with_key(
+        &self,
+        sender: Address,
+        key: NonceKey,
+    ) -> anyhow::Result<U256> {
         let nonce = EntryPoint::new(self.entry_point_address, self.inner.clone().into())
-                .get_nonce(self.sender, validator_for_input)
+                .get_nonce(sender, key.as_u256())
                 .call()
                 .await?;
 
         Ok(nonce)
     }
 
+    /// Reserves the next nonce of the `key` sequence, counting user operations built or sent but
+    /// not yet included.
+    pub async fn next_nonce(
+        &self,
+        sender: Address,
+        key: NonceKey,
+    ) -> anyhow::Result<U256> {
+        if let Some(nonce) = self.nonce_manager.reserve(sender, key) {
+            return Ok(nonce);
+        }
+        let on_chain = self.get_nonce_with_key(sender, key).await?;
+        Ok(self.nonce_manager.reserve_from(sender, key, on_chain))
+    }
+
+    /// Moves the cached `key` sequence up to `EntryPoint.getNonce`.
+    pub async fn resync_nonce(
+        &self,
+        sender: Address,
+        key: NonceKey,
+    ) -> anyhow::Result<U256> {
+        let on_chain = self.get_nonce_with_key(sender, key).await?;
+        self.nonce_manager.resync(sender, key, on_chain);
+        Ok(on_chain)
+    }
+
     pub fn calldata_gen_send_eth(
         &self,
         to_address: Address,
         value: U256,
     ) -> anyhow::Result<Bytes> {
-        let mut mode_code_single = [0u8; 32];
+        self.calldata_gen_execute(to_address, value, Bytes::default())
+    }
+
+    /// Encodes `execute(mode, executionCalldata)` of the account.
+    pub fn calldata_gen_with_mode(
+        &self,
+        mode: ModeCode,
+        execution_calldata: Bytes,
+    ) -> anyhow::Result<Bytes> {
+        MSABasic::new(self.sender, self.inner.clone().into()).calldata_gen_with_mode(mode, execution_calldata)
+    }
+
+    /// Encodes `execute` for a single call, whose execution calldata is
+    /// `abi.encodePacked(target, value, callData)`.
+    pub fn calldata_gen_execute(
+        &self,
+        target: Address,
+        value: U256,
+        func: Bytes,
+    ) -> anyhow::Result<Bytes> {
+        let execution_calldata = Execution::new(target, value, func).encode_single();
+        self.calldata_gen_with_mode(ModeCode::single(), execution_calldata)
+    }
+
+    /// Encodes `execute` with the batch call type, so all `executions` run atomically in one
+    /// user operation.
+    pub fn calldata_gen_execute_batch(
+        &self,
+        executions: &[Execution],
+    ) -> anyhow::Result<Bytes> {
+        self.calldata_gen_with_mode(ModeCode::batch(), encode_batch(executions))
+    }
+
+    /// Uses the single call type for one execution and the batch call type otherwise.
+    pub fn calldata_gen_executions(
+        &self,
+        executions: &[Execution],
+    ) -> anyhow::Result<Bytes> {
+        MSABasic::new(self.sender, self.inner.clone().into()).calldata_gen_executions(executions)
+    }
 
-        let mut execution_calldata = Vec::new();
-        execution_calldata.extend_from_slice(&to_address.as_bytes());
+    pub fn mode_for_executions(executions: &[Execution]) -> ModeCode {
+        if executions.len() == 1 {
+            ModeCode::single()
+        } else {
+            ModeCode::batch()
+        }
+    }
 
-        let mut value_bytes = [0u8; 32];
-        value.to_big_endian(&mut value_bytes);
-        execution_calldata.extend_from_slice(&value_bytes);
+    pub async fn supports_execution_mode(
+        &self,
+        account: Address,
+        mode: ModeCode,
+    ) -> anyhow::Result<bool> {
+        let supported = MSABasic::new(account, self.inner.clone().into())
+            .supports_execution_mode(mode.encode())
+            .call()
+            .await?;
 
-        execution_calldata.extend_from_slice(&[0u8]);
+        Ok(supported)
+    }
 
-        let calldata_for_wallet = MSABasic::new(self.sender, self.inner.clone().into())
-            .encode("execute", (mode_code_single, Bytes::from(execution_calldata)))?;
+    /// Fails with `UnsupportedExecutionMode` when `account` would reject `mode`.
+    pub async fn check_execution_mode(
+        &self,
+        account: Address,
+        mode: ModeCode,
+    ) -> anyhow::Result<()> {
+        if !self.supports_execution_mode(account, mode).await? {
+            return Err(anyhow::anyhow!(
+                UserOpMiddlewareError::<M>::UnsupportedExecutionMode(mode, account)
+            ));
+        }
+        Ok(())
+    }
 
-        Ok(calldata_for_wallet)
+    /// `isModuleInstalled(moduleTypeId, module, additionalContext)` of `account`.
+    pub async fn is_module_installed(
+        &self,
+        account: Address,
+        module_type: ModuleType,
+        module: Address,
+        additional_context: Bytes,
+    ) -> anyhow::Result<bool> {
+        let installed = MSABasic::new(account, self.inner.clone().into())
+            .is_module_installed(module_type.into(), module, additional_context)
+            .call()
+            .await?;
+
+        Ok(installed)
+    }
+
+    /// Validators or executors of `account`, read page by page until the end of the list.
+    pub async fn linked_modules(
+        &self,
+        account: Address,
+        module_type: ModuleType,
+    ) -> anyhow::Result<Vec<Address>> {
+        let msa = MSABasic::new(account, self.inner.clone().into());
+        let mut modules = Vec::new();
+        let mut cursor = SENTINEL;
+        loop {
+            let page_size = U256::from(MODULE_PAGE_SIZE);
+            let (page, next) = match module_type {
+                ModuleType::Validator => msa.get_validators_paginated(cursor, page_size).call().await?,
+                ModuleType::Executor => msa.get_executors_paginated(cursor, page_size).call().await?,
+                ModuleType::Fallback | ModuleType::Hook => {
+                    return Err(anyhow::anyhow!("{} modules are not kept in a list", module_type));
+                },
+            };
+            let page_is_empty = page.is_empty();
+            modules.extend(page);
+            if page_is_empty || next == SENTINEL || next.is_zero() {
+                return Ok(modules);
+            }
+            cursor = next;
+        }
+    }
+
+    /// Reads the full module configuration of `account`: every validator and executor, the
+    /// active hook and the fallback handlers of `selectors`.
+    pub async fn inspect_account(
+        &self,
+        account: Address,
+        selectors: &[[u8; 4]],
+    ) -> anyhow::Result<AccountModules> {
+        if !self.is_deployed(account).await? {
+            return Ok(AccountModules { account, ..Default::default() });
+        }
+
+        let validators = self.linked_modules(account, ModuleType::Validator).await?;
+        let executors = self.linked_modules(account, ModuleType::Executor).await?;
+
+        // `getActiveHook` is only in the Bootstrap ABI; accounts without hook support revert.
+        let hook = match Bootstrap::new(account, self.inner.clone().into()).get_active_hook().call().await {
+            Ok(hook) if hook.is_zero() => None,
+            Ok(hook) => Some(hook),
+            Err(err) if err.is_revert() => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        let msa = MSABasic::new(account, self.inner.clone().into());
+        let mut fallbacks = Vec::new();
+        for selector in selectors {
+            let active = msa.get_active_fallback_handler(*selector).call().await?;
+            if active.handler.is_zero() {
+                continue;
+            }
+            fallbacks.push(ActiveFallbackHandler {
+                selector: selector.to_vec().into(),
+                handler: active.handler,
+                call_type: CallType::from_u8(active.calltype[0])?,
+            });
+        }
+
+        Ok(AccountModules {
+            account,
+            deployed: true,
+            validators,
+            executors,
+            hook,
+            fallbacks,
+        })
+    }
+
+    /// Builds a user operation in which the account installs `module` by calling its own
+    /// `installModule`. For fallback handlers `init_data` is [`module::fallback_install_data`].
+    pub async fn uogen_install_module(
+        &self,
+        module_type: ModuleType,
+        module: Address,
+        init_data: Bytes,
+    ) -> anyhow::Result<UserOperationPartial> {
+        let sender = self.sender;
+        let context = Self::module_context(module_type, &init_data)?;
+        // An account deployed by this user operation has only its initial modules.
+        if self.is_deployed(sender).await?
+            && self.is_module_installed(sender, module_type, module, context).await?
+        {
+            return Err(anyhow::anyhow!(
+                UserOpMiddlewareError::<M>::ModuleAlreadyInstalled(module_type, module, sender)
+            ));
+        }
+
+        let func = MSABasic::new(sender, self.inner.clone().into())
+            .encode("installModule", (U256::from(module_type), module, init_data))?;
+        self.uogen_executions(sender, &[Execution::new(sender, U256::zero(), func)]).await
+    }
+
+    /// Builds a user operation in which the account uninstalls `module` by calling its own
+    /// `uninstallModule`. `de_init_data` is passed to the module's `onUninstall`; for validators
+    /// and executors the previous list entry is looked up and prepended as the account expects,
+    /// for fallback handlers it is [`module::fallback_uninstall_data`].
+    pub async fn uogen_uninstall_module(
+        &self,
+        module_type: ModuleType,
+        module: Address,
+        de_init_data: Bytes,
+    ) -> anyhow::Result<UserOperationPartial> {
+        let sender = self.sender;
+        let context = Self::module_context(module_type, &de_init_data)?;
+        if !self.is_deployed(sender).await?
+            || !self.is_module_installed(sender, module_type, module, context).await?
+        {
+            return Err(anyhow::anyhow!(
+                UserOpMiddlewareError::<M>::ModuleNotInstalled(module_type, module, sender)
+            ));
+        }
+
+        let de_init_data = match module_type {
+            ModuleType::Validator | ModuleType::Executor => {
+                let modules = self.linked_modules(sender, module_type).await?;
+                let prev = match modules.iter().position(|installed| *installed == module) {
+                    Some(0) => SENTINEL,
+                    Some(index) => modules[index - 1],
+                    None => {
+                        return Err(anyhow::anyhow!(
+                            UserOpMiddlewareError::<M>::ModuleNotInstalled(module_type, module, sender)
+                        ));
+                    },
+                };
+                module::linked_uninstall_data(prev, &de_init_data)
+            },
+            ModuleType::Fallback | ModuleType::Hook => de_init_data,
+        };
+
+        let func = MSABasic::new(sender, self.inner.clone().into())
+            .encode("uninstallModule", (U256::from(module_type), module, de_init_data))?;
+        self.uogen_executions(sender, &[Execution::new(sender, U256::zero(), func)]).await
+    }
+
+    /// `additionalContext` for `isModuleInstalled`: fallback handlers are looked up by the
+    /// selector their install or uninstall data starts with.
+    fn module_context(module_type: ModuleType, data: &[u8]) -> anyhow::Result<Bytes> {
+        match module_type {
+            ModuleType::Fallback => {
+                let selector = module::fallback_selector(data)
+                    .ok_or_else(|| anyhow::anyhow!("fallback data must start with the function selector"))?;
+                Ok(module::fallback_context(selector))
+            },
+            _ => Ok(Bytes::default()),
+        }
     }
 
     pub async fn uogen_send_eth(
@@ -225,13 +656,177 @@ impl<M: Middleware + 'static + fmt::Debug + Clone> UserOpMiddleware<M> {
         to_address: Address,
         value: U256,
     ) -> anyhow::Result<UserOperationPartial> {
-        let nonce = self.get_nonce().await?;
-        let calldata = self.calldata_gen_send_eth(to_address, value).unwrap();
-        let mut user_operation = UserOperationPartial {
-            sender: Some(self.sender,),
-            nonce: Some(U256::from(nonce), ),
-            factory: None,
-            factory_data: None,
+        let execution = Execution::new(to_address, value, Bytes::default());
+        self.uogen_executions(self.sender, &[execution]).await
+    }
+
+    /// Builds a user operation calling any contract function, e.g.
+    /// `uogen_call(token, U256::zero(), "transfer(address,uint256)", &[json!(to), json!("1000")])`.
+    /// See [`contract_call::parse_function`] for the accepted forms of `abi_or_signature`.
+    pub async fn uogen_call(
+        &self,
+        target: Address,
+        value: U256,
+        abi_or_signature: &str,
+        args: &[serde_json::Value],
+    ) -> anyhow::Result<UserOperationPartial> {
+        let func = contract_call::encode_call(abi_or_signature, args)?;
+        let execution = Execution::new(target, value, func);
+        self.uogen_executions(self.sender, &[execution]).await
+    }
+
+    pub async fn uogen_execute_batch(
+        &self,
+        executions: &[Execution],
+    ) -> anyhow::Result<UserOperationPartial> {
+        self.uogen_executions(self.sender, executions).await
+    }
+
+    pub async fn uogen_erc20_transfer(
+        &self,
+        token: Address,
+        to_address: Address,
+        amount: U256,
+    ) -> anyhow::Result<UserOperationPartial> {
+        self.uogen_executions(self.sender, &[erc20::transfer(token, to_address, amount)]).await
+    }
+
+    pub async fn uogen_erc20_approve(
+        &self,
+        token: Address,
+        spender: Address,
+        amount: U256,
+    ) -> anyhow::Result<UserOperationPartial> {
+        self.uogen_executions(self.sender, &[erc20::approve(token, spender, amount)]).await
+    }
+
+    pub async fn uogen_erc20_transfer_from(
+        &self,
+        token: Address,
+        from_address: Address,
+        to_address: Address,
+        amount: U256,
+    ) -> anyhow::Result<UserOperationPartial> {
+        let execution = erc20::transfer_from(token, from_address, to_address, amount);
+        self.uogen_executions(self.sender, &[execution]).await
+    }
+
+    /// Builds a batch that submits the owner's EIP-2612 `permit` for the account and then pulls
+    /// `permit.value` tokens from the owner to `to_address`.
+    pub async fn uogen_erc20_permit_transfer(
+        &self,
+        token: Address,
+        permit: Erc20Permit,
+        to_address: Address,
+    ) -> anyhow::Result<UserOperationPartial> {
+        self.uogen_erc20(
+            self.sender,
+            &[Erc20Operation::PermitTransferFrom { token, permit, to: to_address }],
+        )
+        .await
+    }
+
+    /// Builds a user operation for `sender` running all ERC-20 `operations`, batched when
+    /// they need more than one call.
+    pub async fn uogen_erc20(
+        &self,
+        sender: Address,
+        operations: &[Erc20Operation],
+    ) -> anyhow::Result<UserOperationPartial> {
+        let executions = erc20::executions(sender, operations)?;
+        self.uogen_executions(sender, &executions).await
+    }
+
+    /// Builds a user operation for `sender` running `executions`, after checking that the
+    /// account supports the execution mode they need.
+    pub async fn uogen_executions(
+        &self,
+        sender: Address,
+        executions: &[Execution],
+    ) -> anyhow::Result<UserOperationPartial> {
+        self.uogen_executions_with_key(sender, executions, self.nonce_lanes.allocate()).await
+    }
+
+    /// Builds one user operation per entry of `batches`, each on its own nonce lane so they can
+    /// be included independently. Fails with `TooManyParallelBatches` when there are more
+    /// batches than lanes, as the extra ones would queue behind others on a shared lane.
+    pub async fn uogen_parallel(
+        &self,
+        sender: Address,
+        batches: &[Vec<Execution>],
+    ) -> anyhow::Result<Vec<UserOperationPartial>> {
+        let lanes = self.nonce_lanes.lanes();
+        if batches.len() > lanes as usize {
+            return Err(anyhow::anyhow!(
+                UserOpMiddlewareError::<M>::TooManyParallelBatches(batches.len(), lanes)
+            ));
+        }
+        let keys = self.nonce_lanes.allocate_distinct(batches.len() as u32)?;
+        let mut user_operations = Vec::with_capacity(batches.len());
+        for (executions, key) in batches.iter().zip(keys) {
+            user_operations.push(self.uogen_executions_with_key(sender, executions, key).await?);
+        }
+        Ok(user_operations)
+    }
+
+    /// Like [`Self::uogen_executions`] with the nonce taken from the `key` sequence.
+    pub async fn uogen_executions_with_key(
+        &self,
+        sender: Address,
+        executions: &[Execution],
+        key: NonceKey,
+    ) -> anyhow::Result<UserOperationPartial> {
+        let calldata = self.calldata_gen_executions(executions)?;
+        let deployed = self.is_deployed(sender).await?;
+        // An account that is deployed by this user operation cannot be asked yet; the reference
+        // implementation supports the single and batch modes used here.
+        if deployed {
+            self.check_execution_mode(sender, Self::mode_for_executions(executions)).await?;
+        }
+        self.uogen_with_deployment(sender, calldata, deployed, key).await
+    }
+
+    /// Builds an unsigned user operation for `sender` executing `calldata`, with the next nonce
+    /// of the validator's sequence and gas limits and fees filled in from the bundler and node.
+    pub async fn uogen_from_calldata(
+        &self,
+        sender: Address,
+        calldata: Bytes,
+    ) -> anyhow::Result<UserOperationPartial> {
+        let deployed = self.is_deployed(sender).await?;
+        self.uogen_with_deployment(sender, calldata, deployed, self.nonce_lanes.allocate()).await
+    }
+
+    /// Like [`Self::uogen_from_calldata`]; when `sender` is not `deployed` the user operation also
+    /// carries the factory call creating it.
+    async fn uogen_with_deployment(
+        &self,
+        sender: Address,
+        calldata: Bytes,
+        deployed: bool,
+        key: NonceKey,
+    ) -> anyhow::Result<UserOperationPartial> {
+        let (factory, factory_data) = if deployed {
+            (None, None)
+        } else if sender == self.sender {
+            // The factory would deploy another account, which the EntryPoint rejects with AA14.
+            let counterfactual = self.counterfactual_address(self.account_salt, &self.account_init).await?;
+            if counterfactual != sender {
+                return Err(anyhow::anyhow!(
+                    UserOpMiddlewareError::<M>::CounterfactualAddressMismatch(sender, counterfactual)
+                ));
+            }
+            (Some(self.factory), Some(self.get_factory_data()))
+        } else {
+            return Err(anyhow::anyhow!(UserOpMiddlewareError::<M>::AccountNotDeployed(sender)));
+        };
+
+        let nonce = self.next_nonce(sender, key).await?;
+        let user_operation = UserOperationPartial {
+            sender: Some(sender,),
+            nonce: Some(nonce, ),
+            factory,
+            factory_data,
             call_data: Some(calldata,),
             call_gas_limit: Some(U256::from(1_000_000_000u64),),
             verification_gas_limit: Some(U256::from(1_000_000_000u64),),
@@ -245,185 +840,226 @@ impl<M: Middleware + 'static + fmt::Debug + Clone> UserOpMiddleware<M> {
             signature: Some(Bytes::default(),),
         };
 
-        let estimated_gas = self.estimate_user_operation_gas(&user_operation).await.unwrap();
+        let filled = self.fill_gas(user_operation).await;
+        if filled.is_err() {
+            self.nonce_manager.release(sender, nonce);
+        }
+        filled
+    }
 
+    /// Fills fees, gas limits from the bundler's estimation and, with a paymaster service, the
+    /// paymaster fields: stub data before the estimation and the final data once the gas fields
+    /// it signs are known.
+    async fn fill_gas(
+        &self,
+        mut user_operation: UserOperationPartial,
+    ) -> anyhow::Result<UserOperationPartial> {
         let avg_gas_price = self.get_gas_fee().await?;
-
-        user_operation.call_gas_limit = Some(estimated_gas.result.call_gas_limit, );
-        user_operation.verification_gas_limit = Some(estimated_gas.result.verification_gas_limit, );
-        user_operation.pre_verification_gas = Some(estimated_gas.result.pre_verification_gas, );
         user_operation.max_fee_per_gas = Some(avg_gas_price.0, );
         user_operation.max_priority_fee_per_gas = Some(avg_gas_price.1, );
 
-        Ok(user_operation)
-    
-    }
-
-    // pub fn get_factory_data(
-    //     &self,
-    //     salt: U256,
-    // ) -> anyhow::Result<Bytes> {
-        // TODO: need to add a function which make calldata to create account
-        // let bootstrap_contract = Bootstrap::new(bootstrap, contract_provider.clone());
-        // let validators: Vec<BootstrapConfig> = vec![
-        //     BootstrapConfig {
-        //         module: validator,
-        //         data: Bytes::default(),
-        //     },
-        // ];
-        // let executors: Vec<BootstrapConfig> = vec![];
-        // let hook = BootstrapConfig {
-        //     module: Address::zero(), 
-        //     data: Bytes::default(),      
-        // };
-        // let fallbacks: Vec<BootstrapConfig> = vec![];
-
-        // let result: Bytes = bootstrap_contract
-        //     .get_init_msa_calldata(validators, executors, hook, fallbacks)
-        //     .call()
-        //     .await?;
-
-        // let factory_contract = MSAFactory::new(factory_address.clone(), contract_provider.clone());
-        // let factory_data = factory_contract
-        //     .method::<(H256, Bytes), Address>("createAccount", (salt.clone(), result.clone()))?
-        //     .calldata()
-        //     .unwrap();
-
-        // let factory_contract = MSAFactory::new(factory_address.clone(), contract_provider.clone());
-        // let factory_data = factory_contract
-        //     .method::<(H256, Bytes), Address>("createAccount", (salt.clone(), result.clone()))?
-        //     .calldata()
-        //     .unwrap();
-    // }
-
-    pub fn supported_entry_point(&self) -> Address {
-        self.entry_point_address
-    }
+        let stub = match &self.paymaster {
+            Some(paymaster) => {
+                let stub = paymaster
+                    .get_paymaster_stub_data(&user_operation, self.entry_point_address, self.chain_id)
+                    .await?;
+                stub.fill(&mut user_operation);
+                Some(stub)
+            },
+            None => None,
+        };
 
-    pub async fn ask_supported_entry_point(&self) -> Result<JsonRpcResponse<serde_json::Value>, Box<dyn Error>> {
+        let estimated_gas = self.estimate_user_operation_gas(&user_operation).await?;
+
+        user_operation.call_gas_limit = Some(estimated_gas.call_gas_limit, );
+        user_operation.verification_gas_limit = Some(estimated_gas.verification_gas_limit, );
+        user_operation.pre_verification_gas = Some(estimated_gas.pre_verification_gas, );
+
+        if let (Some(paymaster), Some(stub)) = (&self.paymaster, stub) {
+            // Limits given by the paymaster service take precedence over the bundler's.
+            user_operation.paymaster_verification_gas_limit = stub
+                .paymaster_verification_gas_limit
+                .or(Some(estimated_gas.paymaster_verification_gas_limit));
+            user_operation.paymaster_post_op_gas_limit = stub
+                .paymaster_post_op_gas_limit
+                .or(Some(estimated_gas.paymaster_post_op_gas_limit));
+
+            if !stub.is_final {
+                paymaster
+                    .get_paymaster_data(&user_operation, self.entry_point_address, self.chain_id)
+                    .await?
+                    .fill(&mut user_operation);
+            }
+        }
 
-        let params = json!([]);
+        Ok(user_operation)
+    }
 
-        let request = Request {
-            jsonrpc: "2.0".to_string(),
-            method: "eth_supportedEntryPoints".to_string(),
-            params: params,
-            id: 1,
-        };
+    /// `factoryData` deploying `sender`: `MSAFactory.createAccount(salt, initCode)` where
+    /// `initCode` runs `Bootstrap.initMSA` with the configured modules.
+    pub fn get_factory_data(&self) -> Bytes {
+        self.account_init.factory_data(self.bootstrap, self.account_salt)
+    }
 
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&self.rpc_address)
-            .json(&request)
-            .send()
+    /// Reads `MSAFactory.implementation()` of `factory` to derive account addresses offline.
+    pub async fn account_address_deriver(&self) -> anyhow::Result<AccountAddressDeriver> {
+        let implementation = MSAFactory::new(self.factory, self.inner.clone().into())
+            .implementation()
+            .call()
             .await?;
+        Ok(AccountAddressDeriver::new(self.factory, implementation))
+    }
 
-        if !response.status().is_success() {
-            return Err(Box::from(format!("Failed Http request, status code: {}", response.status())));
-        }
+    /// Address `factory` deploys the account created with `salt` and `account_init` at.
+    pub async fn counterfactual_address(
+        &self,
+        salt: H256,
+        account_init: &AccountInit,
+    ) -> anyhow::Result<Address> {
+        let address_deriver = self.account_address_deriver().await?;
+        Ok(address_deriver.address(salt, &account_init.init_code(self.bootstrap)))
+    }
 
-        let response_json: JsonRpcResponse<serde_json::Value> = response.json().await?;
+    pub async fn is_deployed(
+        &self,
+        account: Address,
+    ) -> anyhow::Result<bool> {
+        let code = self.inner
+            .get_code(account, None)
+            .await
+            .map_err(UserOpMiddlewareError::<M>::MiddlewareError)?;
+        Ok(!code.is_empty())
+    }
+
+    pub fn supported_entry_point(&self) -> Address {
+        self.entry_point_address
+    }
 
-        Ok(response_json)
+    pub async fn ask_supported_entry_point(&self) -> anyhow::Result<Vec<Address>> {
+        self.bundler.supported_entry_points().await.map_err(Self::bundler_error)
     }
 
     pub fn chain_id(&self) -> u64 {
         self.chain_id
     }
 
+    /// `None` while the operation is pending.
     pub async fn get_user_operation_receipt(
         &self,
         user_operation_hash: &UserOperationHash,
-    ) -> anyhow::Result<UserOperationReceipt> {
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&self.rpc_address)
-            .json(&json!({
-                "jsonrpc": "2.0",
-                "method": "eth_getUserOperationReceipt",
-                "params": vec![json!(user_operation_hash)],
-                "id": 1,
-            }))
-            .send()
-            .await?
-            .json::<Response<UserOperationReceipt>>()
-            .await?;
-
-        Ok(response.result)
+    ) -> anyhow::Result<Option<UserOperationReceipt>> {
+        self.bundler
+            .get_user_operation_receipt(user_operation_hash)
+            .await
+            .map_err(Self::bundler_error)
     }
 
     pub async fn get_user_operation_by_hash(
         &self,
         user_operation_hash: &UserOperationHash,
-    ) -> anyhow::Result<String> {
-        let client = reqwest::Client::new();
-        let response = client
-            .post(&self.rpc_address)
-            .json(&json!({
-                "jsonrpc": "2.0",
-                "method": "eth_getUserOperationByHash",
-                "params": vec![json!(user_operation_hash)],
-                "id": 1,
-            }))
-            .send()
-            .await?
-            .json::<Response<String>>()
-            .await?;
-        
-        Ok(response.result)
-    }
-
-    async fn handle_response<R>(response: reqwest::Response) -> anyhow::Result<Response<R>>
-    where
-        R: std::fmt::Debug + serde::de::DeserializeOwned,
-    {
-        let str_response = response.text().await?;
-        println!("str: {:?}", str_response.clone());
-        let parsed_response: anyhow::Result<Response<R>> =
-            serde_json::from_str(&str_response).map_err(anyhow::Error::from);
-        println!("parsed response: {:?}", parsed_response);
-
-        match parsed_response {
-            Ok(success_response) => {
-                log::info!("Success {:?}", success_response);
-                Ok(success_response)
-            }
-            Err(_) => {
-                let error_response: ErrorResponse = serde_json::from_str(&str_response)?;
-                log::warn!("Error: {:?}", error_response);
-                let error_message = &error_response.error.message;
-
-                if let Some(captures) =
-                    Regex::new(r"Call gas limit (\d+) is lower than call gas estimation (\d+)")
-                        .unwrap()
-                        .captures(error_message)
-                {
-                    let limit: u64 = captures[1].parse().unwrap();
-                    let estimation: u64 = captures[2].parse().unwrap();
-                    return Err(anyhow::anyhow!(
-                        UserOpMiddlewareError::<M>::CallGasLimitError(limit, estimation,)
-                    ));
-                }
+    ) -> anyhow::Result<Option<UserOperationByHash>> {
+        self.bundler
+            .get_user_operation_by_hash(user_operation_hash)
+            .await
+            .map_err(Self::bundler_error)
+    }
 
-                if let Some(captures) = Regex::new(r"Pre-verification gas (\d+) is lower than calculated pre-verification gas (\d+)")
-                    .unwrap()
-                    .captures(error_message)
-                {
-                    let pre_verification_gas: u64 = captures[1].parse().unwrap();
-                    let calculated_gas: u64 = captures[2].parse().unwrap();
-                    return Err(anyhow::anyhow!(
-                        UserOpMiddlewareError::<M>::PreVerificationGasError(pre_verification_gas, calculated_gas)
-                    ));
+    /// Polls the bundler every `poll_interval` until the operation is included. An included
+    /// operation whose execution reverted is an error carrying the decoded revert reason.
+    pub async fn wait_for_receipt(
+        &self,
+        user_operation_hash: &UserOperationHash,
+        timeout: Duration,
+        poll_interval: Duration,
+    ) -> anyhow::Result<UserOperationReceipt> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(receipt) = self.get_user_operation_receipt(user_operation_hash).await? {
+                if !receipt.success {
+                    return Err(anyhow::anyhow!(UserOpMiddlewareError::<M>::UserOperationReverted(
+                        *user_operation_hash,
+                        Self::revert_reason(&receipt),
+                    )));
                 }
+                return Ok(receipt);
+            }
 
-                if error_message.contains("AA40 over verificationGasLimit") {
-                    return Err(anyhow::anyhow!(
-                        UserOpMiddlewareError::<M>::VerificationGasLimitError
-                    ));
-                }
-                println!("{}", error_message);
-                Err(anyhow::anyhow!(UserOpMiddlewareError::<M>::UnknownError))
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(anyhow::anyhow!(UserOpMiddlewareError::<M>::ReceiptTimeout(
+                    *user_operation_hash,
+                    timeout,
+                )));
             }
+            tokio::time::sleep(poll_interval.min(deadline - now)).await;
+        }
+    }
+
+    /// The receipt's `reason`, or the `UserOperationRevertReason` event when bundlers leave it
+    /// empty.
+    fn revert_reason(receipt: &UserOperationReceipt) -> String {
+        let reason = receipt.reason.trim();
+        if !reason.is_empty() {
+            return match reason.parse::<Bytes>() {
+                Ok(data) => decode_revert_reason(&data),
+                Err(_) => reason.to_string(),
+            };
+        }
+        receipt
+            .logs
+            .iter()
+            .filter_map(|log| parse_log::<UserOperationRevertReasonFilter>(log.clone()).ok())
+            .find(|event| event.user_op_hash == receipt.user_operation_hash.0 .0)
+            .map(|event| decode_revert_reason(&event.revert_reason))
+            .unwrap_or_else(|| "execution reverted without a reason".to_string())
+    }
+
+    /// ERC-7677 stub data of the verifying paymaster, unless its policy rejects the operation.
+    pub fn sponsor_stub_data(
+        &self,
+        user_operation: &UserOperationPartial,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> anyhow::Result<PaymasterStubData> {
+        let verifying_paymaster = self.sponsoring_paymaster(entry_point, chain_id)?;
+        verifying_paymaster.stub_data(&UserOperation::from(user_operation.clone()))
+    }
+
+    /// ERC-7677 paymaster data signed by the verifying paymaster for `user_operation`, whose gas
+    /// limits and fees are final. The policy's rejection reasons are returned as
+    /// [`SponsorshipRejected`](crate::errors::SponsorshipRejected).
+    pub async fn sponsor_user_operation(
+        &self,
+        user_operation: &UserOperationPartial,
+        entry_point: Address,
+        chain_id: u64,
+    ) -> anyhow::Result<PaymasterData> {
+        let verifying_paymaster = self.sponsoring_paymaster(entry_point, chain_id)?;
+        let mut user_operation = UserOperation::from(user_operation.clone());
+        user_operation.paymaster = Some(verifying_paymaster.address);
+        verifying_paymaster.sign(&user_operation).await
+    }
+
+    fn sponsoring_paymaster(&self, entry_point: Address, chain_id: u64) -> anyhow::Result<&VerifyingPaymaster> {
+        let verifying_paymaster = self.verifying_paymaster
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!(UserOpMiddlewareError::<M>::VerifyingPaymasterNotConfigured))?;
+        if entry_point != self.entry_point_address {
+            return Err(anyhow::anyhow!(UserOpMiddlewareError::<M>::UnsupportedEntryPoint(entry_point)));
+        }
+        if chain_id != verifying_paymaster.chain_id {
+            return Err(anyhow::anyhow!(UserOpMiddlewareError::<M>::UnsupportedChain(chain_id)));
+        }
+        Ok(verifying_paymaster)
+    }
+
+    fn bundler_error(err: BundlerClientError) -> anyhow::Error {
+        match err {
+            BundlerClientError::Rpc(error) => {
+                log::warn!("bundler rejected the request: {}", error);
+                anyhow::anyhow!(UserOpMiddlewareError::<M>::from_rpc_error(error))
+            },
+            err => anyhow::anyhow!(err),
         }
     }
 
@@ -484,41 +1120,115 @@ impl<M: Middleware + 'static + fmt::Debug + Clone> UserOpMiddleware<M> {
         )
     }
 
+    /// Hash to be signed for `uo` on this middleware's entry point and chain.
+    pub fn uo_hash(&self, uo: &UserOperation) -> anyhow::Result<UserOperationHash> {
+        Ok(uo.hash(&self.entry_point_address, &U256::from(self.chain_id))?)
+    }
+
+    /// Signing strategy of the validator `uo` is validated by, which its nonce key selects.
+    pub fn signing_strategy(&self, uo: &UserOperation) -> SigningStrategy {
+        let (key, _) = split_nonce(uo.nonce);
+        self.signing.for_validator(key.validator())
+    }
+
+    /// Digest the wallet signs for `uo`: its validator's strategy, in its EIP-712 domain.
+    pub fn signing_digest(&self, uo: &UserOperation) -> anyhow::Result<H256> {
+        let (key, _) = split_nonce(uo.nonce);
+        Ok(self.signing.digest(key.validator(), uo, self.entry_point_address, self.chain_id)?)
+    }
+
     pub async fn sign_uo(&self, uo: UserOperation) -> anyhow::Result<UserOperation> {
-        let h = uo.hash(&self.entry_point_address, &U256::from(self.chain_id));
-        let sig = self.wallet.sign_message(h.0.as_bytes()).await?;
+        let sig = self.wallet.sign_hash(self.signing_digest(&uo)?)?;
         let res_uo = uo.clone().signature(sig.to_vec().into());
         Ok(res_uo)
     }
 
+    /// `(max_fee_per_gas, max_priority_fee_per_gas)` for the configured fee strategy.
     pub async fn get_gas_fee(&self) -> anyhow::Result<(U256, U256)> {
-        let latest_block_number = self.provider().get_block_number().await?;
-        let latest_block = self.provider().get_block_with_txs(latest_block_number).await?;
-        let transactions = latest_block.unwrap().transactions;
-
-        let mut total_max_fee_per_gas = U256::from(0);
-        let mut total_max_priority_fee_per_gas = U256::from(0);
-        let mut tx_count = 0;
-
-        for tx in transactions {
-            if let (Some(max_fee_per_gas), Some(max_priority_fee_per_gas)) = (tx.max_fee_per_gas, tx.max_priority_fee_per_gas) {
-                // u64 から BigInt に変換
-                let max_fee_per_gas_value = U256::from(max_fee_per_gas);
-                let max_priority_fee_per_gas_value = U256::from(max_priority_fee_per_gas);
-    
-                // 合計値を更新
-                total_max_fee_per_gas += max_fee_per_gas_value;
-                total_max_priority_fee_per_gas += max_priority_fee_per_gas_value;
-                tx_count += 1;
+        let fees = self.estimate_fees().await?;
+        Ok((fees.max_fee_per_gas, fees.max_priority_fee_per_gas))
+    }
+
+    /// Fees quoted by the bundler's fee source when one is set, otherwise or when the bundler
+    /// fails, estimated from the node's `eth_feeHistory`.
+    pub async fn estimate_fees(&self) -> anyhow::Result<GasFees> {
+        if let Some(fee_source) = &self.bundler_fee_source {
+            match self.bundler_fees(fee_source.as_ref()).await {
+                Ok(fees) => return Ok(fees),
+                Err(err) => log::warn!("bundler fee quote failed, estimating from the node: {:#}", err),
             }
         }
-    
-        let avg_max_fee_per_gas = total_max_fee_per_gas / tx_count;
-        let avg_max_priority_fee_per_gas = total_max_priority_fee_per_gas / tx_count;
-    
-        Ok((avg_max_fee_per_gas, avg_max_priority_fee_per_gas))
+        self.node_fees(self.fee_strategy).await
+    }
+
+    async fn bundler_fees(&self, fee_source: &dyn BundlerFeeSource) -> anyhow::Result<GasFees> {
+        match fee_source.quote(&self.bundler, self.fee_strategy).await? {
+            BundlerFeeQuote::Fees(fees) => Ok(fees),
+            BundlerFeeQuote::PriorityFee(priority_fee) => {
+                let history = self.inner
+                    .fee_history(1u64, BlockNumber::Latest, &[])
+                    .await
+                    .map_err(UserOpMiddlewareError::<M>::MiddlewareError)?;
+                let next_base_fee = *history
+                    .base_fee_per_gas
+                    .last()
+                    .ok_or_else(|| anyhow::anyhow!("fee history has no base fee"))?;
+                Ok(fee_oracle::fees_with_priority_fee(next_base_fee, priority_fee, self.fee_strategy))
+            },
+        }
+    }
 
-        
+    pub async fn node_fees(&self, strategy: FeeStrategy) -> anyhow::Result<GasFees> {
+        let history = self.inner
+            .fee_history(FEE_HISTORY_BLOCKS, BlockNumber::Latest, &[strategy.reward_percentile()])
+            .await
+            .map_err(UserOpMiddlewareError::<M>::MiddlewareError)?;
+        fee_oracle::fees_from_history(&history, strategy)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::test_fixtures::{entry_point, wallet};
+    use ethers::abi::AbiEncode;
+    use ethers::providers::{MockProvider, Provider};
+
+    fn middleware() -> (UserOpMiddleware<Provider<MockProvider>>, MockProvider) {
+        let (provider, mock) = Provider::mocked();
+        let uo_middleware = UserOpMiddleware::new(
+            provider,
+            entry_point(),
+            "http://localhost:4337",
+            wallet(),
+            Address::from_low_u64_be(1),
+            "0x503b54Ed1E62365F0c9e4caF1479623b08acbe77".parse().unwrap(),
+            Address::zero(),
+            Address::zero(),
+        );
+        (uo_middleware, mock)
+    }
+
+    #[tokio::test]
+    async fn builds_before_any_send_get_distinct_nonces() {
+        let (uo_middleware, mock) = middleware();
+        let key = uo_middleware.nonce_key();
+        mock.push::<Bytes, Bytes>(key.nonce(5).encode().into()).unwrap();
 
+        let first = uo_middleware.next_nonce(uo_middleware.sender, key).await.unwrap();
+        let second = uo_middleware.next_nonce(uo_middleware.sender, key).await.unwrap();
+        assert_eq!((first, second), (key.nonce(5), key.nonce(6)));
+    }
+
+    #[test]
+    fn rpc_signing_needs_the_configured_token() {
+        let (uo_middleware, _) = middleware();
+        assert!(!uo_middleware.authorizes_rpc_signing(Some("")));
+
+        let uo_middleware = uo_middleware.with_rpc_signing_token(Some("secret".to_string()));
+        assert!(uo_middleware.authorizes_rpc_signing(Some("secret")));
+        assert!(!uo_middleware.authorizes_rpc_signing(Some("secreT")));
+        assert!(!uo_middleware.authorizes_rpc_signing(Some("secret2")));
+        assert!(!uo_middleware.authorizes_rpc_signing(None));
+    }
 }
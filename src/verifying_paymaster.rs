@@ -0,0 +1,207 @@
+use crate::errors::Uint128Overflow;
+use crate::paymaster::{PaymasterData, PaymasterSponsor, PaymasterStubData};
+use crate::sponsorship::{SponsorshipEngine, SponsorshipPolicy};
+use crate::primitives::packed_user_operation::{pack_uints, uint128, PackedUserOperation};
+use crate::primitives::user_operation::UserOperation;
+use ethers::{
+    abi::{self, Token},
+    signers::{LocalWallet, Signer},
+    types::{Address, Bytes, H256, U256},
+    utils::keccak256,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Gas the `VerifyingPaymaster` needs to check its signature, quoted in the stub data.
+pub const VERIFYING_PAYMASTER_VERIFICATION_GAS_LIMIT: u64 = 100_000;
+
+/// Well-formed signature that recovers to an unrelated address, so validation runs its full
+/// course during estimation and only reports a signature failure.
+pub(crate) const DUMMY_SIGNATURE: [u8; 65] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xf0,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x7a, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
+    0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa,
+    0x1c,
+];
+
+/// Signer of the eth-infinitism v0.7 `VerifyingPaymaster` deployed at `address`: it sponsors
+/// every user operation carrying a signature of `signer` over the operation and its validity
+/// window. `paymasterData` is `abi.encode(uint48 validUntil, uint48 validAfter) ++ signature`.
+///
+/// Nothing is signed for an operation its sponsorship policy rejects.
+#[derive(Debug)]
+pub struct VerifyingPaymaster {
+    pub address: Address,
+    signer: LocalWallet,
+    pub chain_id: u64,
+    pub sponsorship: SponsorshipEngine,
+}
+
+impl VerifyingPaymaster {
+    /// `signer` must be the paymaster's `verifyingSigner`; keep it apart from wallet keys. The
+    /// default policy sponsors every operation, so set one with [`Self::with_policy`].
+    pub fn new(address: Address, signer: LocalWallet, chain_id: u64) -> Self {
+        Self {
+            address,
+            signer,
+            chain_id,
+            sponsorship: SponsorshipEngine::default(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: SponsorshipPolicy) -> Self {
+        self.sponsorship = SponsorshipEngine::new(policy);
+        self
+    }
+
+    pub fn signer(&self) -> Address {
+        self.signer.address()
+    }
+
+    /// `VerifyingPaymaster.getHash(userOp, validUntil, validAfter)`. It commits to every field
+    /// except `paymasterData` and the signature, including the paymaster gas limits. Fails when
+    /// one of them does not fit in `uint128`.
+    pub fn hash(
+        &self,
+        user_operation: &UserOperation,
+        valid_until: u64,
+        valid_after: u64,
+    ) -> Result<H256, Uint128Overflow> {
+        let packed = PackedUserOperation::try_from(user_operation.clone())?;
+        let paymaster_gas_limits = pack_uints(
+            uint128("paymasterVerificationGasLimit", user_operation.paymaster_verification_gas_limit)?,
+            uint128("paymasterPostOpGasLimit", user_operation.paymaster_post_op_gas_limit)?,
+        );
+        let encoded = abi::encode(&[
+            Token::Address(packed.sender),
+            Token::Uint(packed.nonce),
+            Token::FixedBytes(keccak256(&packed.init_code).to_vec()),
+            Token::FixedBytes(keccak256(&packed.call_data).to_vec()),
+            Token::FixedBytes(packed.account_gas_limits.as_bytes().to_vec()),
+            Token::Uint(U256::from_big_endian(paymaster_gas_limits.as_bytes())),
+            Token::Uint(packed.pre_verification_gas),
+            Token::FixedBytes(packed.gas_fees.as_bytes().to_vec()),
+            Token::Uint(U256::from(self.chain_id)),
+            Token::Address(self.address),
+            Token::Uint(U256::from(valid_until)),
+            Token::Uint(U256::from(valid_after)),
+        ]);
+        Ok(H256::from(keccak256(encoded)))
+    }
+
+    /// Paymaster data for estimation; the gas limits are the ones the signed data will need.
+    /// Fails when the policy already rejects the operation.
+    pub fn stub_data(&self, user_operation: &UserOperation) -> anyhow::Result<PaymasterStubData> {
+        self.sponsorship.pre_check(user_operation, unix_time()?)?;
+        Ok(PaymasterStubData {
+            sponsor: self.sponsor(),
+            paymaster: self.address,
+            paymaster_data: paymaster_data(0, 0, &DUMMY_SIGNATURE),
+            paymaster_verification_gas_limit: Some(U256::from(VERIFYING_PAYMASTER_VERIFICATION_GAS_LIMIT)),
+            // The paymaster returns no context, so the EntryPoint never calls postOp.
+            paymaster_post_op_gas_limit: Some(U256::zero()),
+            is_final: false,
+        })
+    }
+
+    /// Signs `user_operation` if the policy approves it, for the validity window it grants.
+    pub async fn sign(&self, user_operation: &UserOperation) -> anyhow::Result<PaymasterData> {
+        let grant = self.sponsorship.approve(user_operation, unix_time()?)?;
+        self.sign_window(user_operation, grant.valid_until, grant.valid_after).await
+    }
+
+    pub async fn sign_window(
+        &self,
+        user_operation: &UserOperation,
+        valid_until: u64,
+        valid_after: u64,
+    ) -> anyhow::Result<PaymasterData> {
+        let hash = self.hash(user_operation, valid_until, valid_after)?;
+        // The paymaster recovers the signer from the EIP-191 message of the hash.
+        let signature = self.signer.sign_message(hash.as_bytes()).await?;
+        Ok(PaymasterData {
+            paymaster: self.address,
+            paymaster_data: paymaster_data(valid_until, valid_after, &signature.to_vec()),
+        })
+    }
+
+    fn sponsor(&self) -> Option<PaymasterSponsor> {
+        self.sponsorship.policy.sponsor_name.clone().map(|name| PaymasterSponsor { name, icon: None })
+    }
+}
+
+fn unix_time() -> anyhow::Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// `abi.encode(uint48 validUntil, uint48 validAfter) ++ signature`.
+pub fn paymaster_data(valid_until: u64, valid_after: u64, signature: &[u8]) -> Bytes {
+    let mut data = abi::encode(&[Token::Uint(U256::from(valid_until)), Token::Uint(U256::from(valid_after))]);
+    data.extend_from_slice(signature);
+    data.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::{
+        primitives::{keccak256 as a_keccak256, Address as a_Address, FixedBytes, U256 as a_U256},
+        sol_types::SolValue,
+    };
+    use crate::primitives::test_fixtures::{user_operation, wallet, SEPOLIA_CHAIN_ID};
+    use ethers::types::Signature;
+
+    fn paymaster() -> VerifyingPaymaster {
+        VerifyingPaymaster::new("0x0000000000000039cd5e8aE05257CE51C473ddd1".parse().unwrap(), wallet(), SEPOLIA_CHAIN_ID)
+    }
+
+    fn uo() -> UserOperation {
+        user_operation().paymaster_verification_gas_limit(U256::from(100_000u64))
+    }
+
+    #[test]
+    fn hash_matches_verifying_paymaster_encoding() {
+        let paymaster = paymaster();
+        let uo = uo();
+        let packed = PackedUserOperation::try_from(uo.clone()).unwrap();
+
+        let u256 = |value: U256| a_U256::from_limbs(value.0);
+        let expected = a_keccak256(
+            (
+                a_Address::from(packed.sender.0),
+                u256(packed.nonce),
+                a_keccak256(packed.init_code.as_ref()),
+                a_keccak256(packed.call_data.as_ref()),
+                FixedBytes::from(packed.account_gas_limits.0),
+                (a_U256::from(100_000u64) << 128) | a_U256::ZERO,
+                u256(packed.pre_verification_gas),
+                FixedBytes::from(packed.gas_fees.0),
+                a_U256::from(SEPOLIA_CHAIN_ID),
+                a_Address::from(paymaster.address.0),
+                a_U256::from(1_700_000_000u64),
+                a_U256::from(5u64),
+            )
+                .abi_encode_params(),
+        );
+        assert_eq!(paymaster.hash(&uo, 1_700_000_000, 5).unwrap().0, expected.0);
+        assert_ne!(paymaster.hash(&uo, 1_700_000_000, 5).unwrap(), paymaster.hash(&uo, 1_700_000_001, 5).unwrap());
+    }
+
+    #[tokio::test]
+    async fn signs_recoverable_paymaster_data() {
+        let paymaster = paymaster();
+        let signed = paymaster.sign_window(&uo(), 1_700_000_000, 5).await.unwrap();
+        let data = signed.paymaster_data;
+        assert_eq!(data.len(), 64 + 65);
+        assert_eq!(U256::from_big_endian(&data[..32]), U256::from(1_700_000_000u64));
+        assert_eq!(U256::from_big_endian(&data[32..64]), U256::from(5u64));
+
+        let signature = Signature::try_from(&data[64..]).unwrap();
+        let hash = paymaster.hash(&uo(), 1_700_000_000, 5).unwrap();
+        assert_eq!(signature.recover(hash.as_bytes()).unwrap(), paymaster.signer());
+
+        // No call to decode, so the default policy has nothing to reject.
+        let stub = paymaster.stub_data(&uo().call_data(Bytes::default())).unwrap();
+        assert_eq!(stub.paymaster_data.len(), data.len());
+    }
+}